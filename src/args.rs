@@ -1,10 +1,13 @@
-use clap::{Parser, builder::PossibleValuesParser};
-use crate::globals::LOG_LEVELS;
+use clap::{Parser, Subcommand, builder::PossibleValuesParser};
+use crate::globals::{HASH_TYPES, LOG_LEVELS};
 
 /// Duhastsrv usage...
 #[derive(Parser, Debug)]
-#[command(version, about, long_about = None)]
+#[command(version, about, long_about = None, subcommand_negates_reqs = true)]
 pub struct Args {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
     /// Host to run on.
     #[arg(long, default_value = "127.0.0.1")]
     pub host: String,
@@ -13,6 +16,15 @@ pub struct Args {
     #[arg(long, default_value_t = 1337)]
     pub port: u16,
 
+    /// Port to serve the nsrlsvr line protocol on, for nsrllookup based
+    /// clients, 0 to disable.
+    #[arg(long, default_value_t = 0)]
+    pub nsrl_port: u16,
+
+    /// Port to serve the HTTP/JSON API on, 0 to disable.
+    #[arg(long, default_value_t = 0)]
+    pub http_port: u16,
+
     /// The log level to use.
     #[arg(
         long,
@@ -21,15 +33,108 @@ pub struct Args {
     )]
     pub log_level: String,
 
-    /// Hash input file, sorted uppercase. 
-    #[arg(long, required = true)]
-    pub hash_file: String,
+    /// Hash input file, one hex hash per line, a binary index, an NSRL RDS 2.x
+    /// NSRLFile.txt or an NSRL RDSv3 SQLite database.
+    /// Repeat with an algorithm prefix (e.g. "sha1:hashes.txt") to serve
+    /// several algorithms.
+    #[arg(long, required_unless_present = "follow", action = clap::ArgAction::Append)]
+    pub hash_file: Vec<String>,
+
+    /// Named read-only hash set, "name:category:[algorithm:]path" with a
+    /// category of "known-good" or "known-bad". Repeat per set & algorithm.
+    #[arg(long, action = clap::ArgAction::Append)]
+    pub hash_set: Vec<String>,
+
+    /// Hash algorithm of hash files given without a prefix.
+    #[arg(
+        long,
+        default_value = "md5",
+        value_parser = PossibleValuesParser::new(HASH_TYPES)
+    )]
+    pub hash_type: String,
+
+    /// Refuse to start on unsorted text hash files instead of sorting them.
+    #[arg(long, action = clap::ArgAction::SetTrue, default_value_t = false)]
+    pub require_sorted: bool,
+
+    /// Serve binary indexes from a read-only memory map instead of the heap.
+    #[arg(long, action = clap::ArgAction::SetTrue, default_value_t = false)]
+    pub mmap: bool,
+
+    /// NSRL RDSv3 SQLite database to serve file metadata of hits from.
+    #[arg(long, default_value = "")]
+    pub metadata: String,
 
     /// Merge change files into hash_file.
     #[arg(long, action = clap::ArgAction::SetTrue, default_value_t = false)]
     pub merge: bool,
 
+    /// Merge change files into hash_file every N seconds while serving,
+    /// 0 to only merge on request.
+    #[arg(long, default_value_t = 0)]
+    pub merge_interval: u64,
+
+    /// Directory to keep base indexes, change files, backups & a lock file in.
+    /// Hash files are imported into it on the first start. Without it,
+    /// change files go to ".change-files" & merges rewrite the hash files.
+    #[arg(long, default_value = "")]
+    pub data_dir: String,
+
+    /// Follow a leader at "host:port", replicating its hash set instead of
+    /// reading "--hash-file". Followers reject updates, deletions & admin
+    /// commands.
+    #[arg(
+        long,
+        default_value = "",
+        conflicts_with_all = ["hash_file", "merge", "merge_interval", "data_dir"]
+    )]
+    pub follow: String,
+
     /// Test the search with a hash. 
     #[arg(long, default_value = "")]
     pub test: String,
 }
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Convert a hash file into a binary index for fast startup.
+    Convert(ConvertArgs),
+    /// Snapshot the hash set of a running server, including runtime changes.
+    Snapshot(SnapshotArgs),
+    /// Restore the hash set of a running server from a snapshot.
+    Restore(SnapshotArgs),
+}
+
+#[derive(Parser, Debug)]
+pub struct ConvertArgs {
+    /// Hash input file, any format accepted by "--hash-file".
+    #[arg(long)]
+    pub input: String,
+
+    /// Binary index output file.
+    #[arg(long)]
+    pub output: String,
+
+    /// Hash algorithm to convert.
+    #[arg(
+        long,
+        default_value = "md5",
+        value_parser = PossibleValuesParser::new(HASH_TYPES)
+    )]
+    pub hash_type: String,
+}
+
+#[derive(Parser, Debug)]
+pub struct SnapshotArgs {
+    /// Host of the running server.
+    #[arg(long, default_value = "127.0.0.1")]
+    pub host: String,
+
+    /// Port of the running server.
+    #[arg(long, default_value_t = 1337)]
+    pub port: u16,
+
+    /// Snapshot name, letters, digits, "-", "_" & ".".
+    #[arg(long)]
+    pub name: String,
+}
@@ -0,0 +1,779 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+
+use log::{info, warn};
+
+use tokio::sync::broadcast;
+
+use crate::globals::DEFAULT_SET_NAME;
+use crate::hash::HashAlgorithm;
+use crate::ingest::{HashFile, ReadOptions};
+use crate::layout::Layout;
+use crate::metadata::Metadata;
+use crate::mmap::Mmap;
+use crate::replication::{REPLICATION_BACKLOG, Replication};
+
+// Set membership is reported as a u32 bitmask
+pub const MAX_SETS: usize = 32;
+
+//
+// Classification of a named hash set.
+//
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Category {
+    KnownGood,
+    KnownBad,
+}
+
+impl Category {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "known-good" => Some(Self::KnownGood),
+            "known-bad" => Some(Self::KnownBad),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::KnownGood => "known-good",
+            Self::KnownBad => "known-bad",
+        }
+    }
+
+    //
+    // Categories are bit flags, so matches of several sets can be combined.
+    //
+    pub fn bit(&self) -> u8 {
+        match self {
+            Self::KnownGood => 1,
+            Self::KnownBad => 2,
+        }
+    }
+}
+
+//
+// Named & categorized set of hash tables, at most one per algorithm.
+//
+pub struct NamedSet {
+    name: String,
+    category: Category,
+    tables: HashMap<HashAlgorithm, SharedTable>,
+}
+
+impl NamedSet {
+    pub fn new(name: &str, category: Category) -> Self {
+        NamedSet {
+            name: name.to_owned(),
+            category,
+            tables: HashMap::new(),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn category(&self) -> Category {
+        self.category
+    }
+
+    //
+    // Add a table, replacing any existing table of the same algorithm.
+    //
+    pub fn add(&mut self, table: HashTable) {
+        self.tables.insert(table.algorithm(), SharedTable::new(table));
+    }
+
+    pub fn get(&self, algorithm: HashAlgorithm) -> Option<&SharedTable> {
+        self.tables.get(&algorithm)
+    }
+
+    pub fn algorithms(&self) -> Vec<HashAlgorithm> {
+        let mut algorithms: Vec<HashAlgorithm> = self.tables.keys().copied().collect();
+        algorithms.sort_by_key(|algorithm| algorithm.id());
+        algorithms
+    }
+}
+
+//
+// Registry of named hash sets.
+// The first set is the default known-good set, the only one receiving
+// runtime updates. Other sets are read-only reference sets.
+// Optional NSRL metadata is read-only, so it is shared as is.
+// The files of the default set are kept to merge change files into, the
+// layout tells where change files & backups go. Changes of the default set
+// are broadcast to followers, a follower itself is read-only.
+//
+pub struct Database {
+    sets: Vec<NamedSet>,
+    metadata: Option<Metadata>,
+    files: Vec<HashFile>,
+    options: ReadOptions,
+    layout: Layout,
+    replication: broadcast::Sender<Replication>,
+    read_only: bool,
+    // Held while writing or merging change files, so a merge never sees
+    // a change that is only partly written
+    change_log: tokio::sync::Mutex<()>,
+}
+
+impl Default for Database {
+    fn default() -> Self {
+        Database {
+            sets: vec![NamedSet::new(DEFAULT_SET_NAME, Category::KnownGood)],
+            metadata: None,
+            files: vec![],
+            options: ReadOptions::default(),
+            layout: Layout::default(),
+            replication: broadcast::channel(REPLICATION_BACKLOG).0,
+            read_only: false,
+            change_log: tokio::sync::Mutex::new(()),
+        }
+    }
+}
+
+impl Database {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    //
+    // Add a table to the default set.
+    //
+    pub fn add(&mut self, table: HashTable) {
+        self.sets[0].add(table);
+    }
+
+    //
+    // Table of the default set.
+    //
+    pub fn get(&self, algorithm: HashAlgorithm) -> Option<&SharedTable> {
+        self.sets[0].get(algorithm)
+    }
+
+    pub fn add_set(&mut self, set: NamedSet) {
+        self.sets.push(set);
+    }
+
+    pub fn sets(&self) -> &[NamedSet] {
+        &self.sets
+    }
+
+    //
+    // Algorithms served by any set.
+    //
+    pub fn algorithms(&self) -> Vec<HashAlgorithm> {
+        let mut algorithms: Vec<HashAlgorithm> = vec![];
+        for set in &self.sets {
+            for algorithm in set.tables.keys() {
+                if !algorithms.contains(algorithm) {
+                    algorithms.push(*algorithm);
+                }
+            }
+        }
+        algorithms
+    }
+
+    pub fn set_metadata(&mut self, metadata: Metadata) {
+        self.metadata = Some(metadata);
+    }
+
+    pub fn metadata(&self) -> Option<&Metadata> {
+        self.metadata.as_ref()
+    }
+
+    //
+    // Files the default set was read from & the options used to read them.
+    //
+    pub fn set_files(&mut self, files: Vec<HashFile>, options: ReadOptions) {
+        self.files = files;
+        self.options = options;
+    }
+
+    pub fn files(&self) -> &[HashFile] {
+        &self.files
+    }
+
+    pub fn options(&self) -> ReadOptions {
+        self.options
+    }
+
+    pub fn set_layout(&mut self, layout: Layout) {
+        self.layout = layout;
+    }
+
+    pub fn layout(&self) -> &Layout {
+        &self.layout
+    }
+
+    pub fn change_log(&self) -> &tokio::sync::Mutex<()> {
+        &self.change_log
+    }
+
+    //
+    // Send a replication event to all followers, must be called holding the
+    // change log so followers see events in the order of the change files.
+    //
+    pub fn replicate(&self, event: Replication) {
+        // Fails only without any follower
+        let _ = self.replication.send(event);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Replication> {
+        self.replication.subscribe()
+    }
+
+    pub fn set_read_only(&mut self, read_only: bool) {
+        self.read_only = read_only;
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+}
+
+// Deltas larger than this are folded into the base in the background
+const COMPACTION_THRESHOLD: usize = 1 << 16;
+
+//
+// Hash table shared between connections as an immutable snapshot.
+// Readers take the current snapshot & search it without holding any lock,
+// so queries never wait on each other or on updates. Writers are serialized,
+// modify a private copy of the small delta & swap it in, the large base is
+// shared between snapshots & never modified.
+//
+pub struct SharedTable {
+    current: RwLock<Arc<Snapshot>>,
+    writer: Mutex<()>,
+}
+
+impl SharedTable {
+    pub fn new(table: HashTable) -> Self {
+        let algorithm = table.algorithm();
+        SharedTable {
+            current: RwLock::new(Arc::new(Snapshot {
+                base: Arc::new(table),
+                frozen: None,
+                delta: Arc::new(Delta::new(algorithm)),
+            })),
+            writer: Mutex::new(()),
+        }
+    }
+
+    pub fn snapshot(&self) -> Arc<Snapshot> {
+        // The lock only guards an Arc clone, a poisoned lock still holds a valid snapshot
+        self.current.read().unwrap_or_else(|error| error.into_inner()).clone()
+    }
+
+    fn publish(&self, snapshot: Snapshot) {
+        *self.current.write().unwrap_or_else(|error| error.into_inner()) = Arc::new(snapshot);
+    }
+
+    //
+    // Apply `change` to a copy of the current snapshot & publish the copy.
+    // Readers holding the previous snapshot keep using it until they are done.
+    //
+    pub fn update<F, R>(&self, change: F) -> R
+    where F: FnOnce(&mut Snapshot) -> R, {
+        let _writer = self.writer.lock().unwrap_or_else(|error| error.into_inner());
+        let mut snapshot = Snapshot::clone(&self.snapshot());
+        let result = change(&mut snapshot);
+        self.publish(snapshot);
+        result
+    }
+
+    //
+    // Replace the whole table, dropping the delta.
+    // Used once the delta has been written to the base file.
+    //
+    pub fn replace(&self, table: HashTable) {
+        let _writer = self.writer.lock().unwrap_or_else(|error| error.into_inner());
+        self.publish(Snapshot {
+            base: Arc::new(table),
+            frozen: None,
+            delta: Arc::new(Delta::new(self.snapshot().base.algorithm())),
+        });
+    }
+
+    //
+    // Replace the delta by replayed changes, sorted added & removed digests.
+    // Only the changes that differ from the base are kept, so the delta is
+    // built without any shifting. Meant for startup, before serving.
+    //
+    pub fn replay(&self, added: &HashTable, removed: &HashTable) {
+        let _writer = self.writer.lock().unwrap_or_else(|error| error.into_inner());
+        let snapshot = self.snapshot();
+
+        let mut delta = Delta::new(snapshot.base.algorithm());
+        for digest in added.iter() {
+            if snapshot.base.binary_search(digest).is_err() {
+                delta.added.push(digest);
+            }
+        }
+        for digest in removed.iter() {
+            if snapshot.base.binary_search(digest).is_ok() {
+                delta.removed.push(digest);
+            }
+        }
+
+        info!("Replayed {} {} insertions & {} deletions.",
+            delta.added.len(), snapshot.base.algorithm().name(), delta.removed.len());
+
+        self.publish(Snapshot {
+            base: snapshot.base.clone(),
+            frozen: None,
+            delta: Arc::new(delta),
+        });
+    }
+
+    pub fn needs_compaction(&self) -> bool {
+        let snapshot = self.snapshot();
+        snapshot.frozen.is_none() && snapshot.delta.len() >= COMPACTION_THRESHOLD
+    }
+
+    //
+    // Fold the delta into a new base.
+    // The delta is frozen & replaced by an empty one, so updates continue
+    // while the new base is built. Only one compaction runs at a time.
+    //
+    pub fn compact(&self) {
+        let snapshot = {
+            let _writer = self.writer.lock().unwrap_or_else(|error| error.into_inner());
+            let snapshot = self.snapshot();
+            if snapshot.frozen.is_some() || snapshot.delta.is_empty() {
+                return;
+            }
+            self.publish(Snapshot {
+                base: snapshot.base.clone(),
+                frozen: Some(snapshot.delta.clone()),
+                delta: Arc::new(Delta::new(snapshot.base.algorithm())),
+            });
+            snapshot
+        };
+
+        info!("Compacting {} {} changes into {} hashes.",
+            snapshot.delta.len(), snapshot.base.algorithm().name(), snapshot.base.len());
+
+        let (base, _, _) = snapshot.base.apply(&snapshot.delta.added, &snapshot.delta.removed);
+
+        let _writer = self.writer.lock().unwrap_or_else(|error| error.into_inner());
+        let current = self.snapshot();
+        // Replaced while compacting, the new base already holds the changes
+        if !Arc::ptr_eq(&current.base, &snapshot.base) {
+            return;
+        }
+        self.publish(Snapshot {
+            base: Arc::new(base),
+            frozen: None,
+            delta: current.delta.clone(),
+        });
+
+        info!("Finished compacting {} hashes.", snapshot.base.algorithm().name());
+    }
+}
+
+//
+// Point in time view of a shared table.
+// Lookups check the delta, then the frozen delta of a running compaction,
+// then the base. A delta records hashes added on top of & removed from the
+// layers below it.
+//
+#[derive(Clone)]
+pub struct Snapshot {
+    base: Arc<HashTable>,
+    frozen: Option<Arc<Delta>>,
+    delta: Arc<Delta>,
+}
+
+impl Snapshot {
+    //
+    // The base without the changes on top of it.
+    //
+    pub fn base(&self) -> &HashTable {
+        &self.base
+    }
+
+    //
+    // The base with all changes on top of it, as a new table.
+    //
+    pub fn to_table(&self) -> HashTable {
+        let mut table: Option<HashTable> = None;
+        for delta in [self.frozen.as_ref(), Some(&self.delta)].into_iter().flatten() {
+            if !delta.is_empty() {
+                let below = table.as_ref().unwrap_or(&self.base);
+                table = Some(below.apply(&delta.added, &delta.removed).0);
+            }
+        }
+        table.unwrap_or_else(|| HashTable::clone(&self.base))
+    }
+
+    pub fn contains(&self, digest: &[u8]) -> bool {
+        for delta in [Some(&self.delta), self.frozen.as_ref()].into_iter().flatten() {
+            if let Some(present) = delta.get(digest) {
+                return present;
+            }
+        }
+        self.base.binary_search(digest).is_ok()
+    }
+
+    //
+    // Returns false if the digest already exists.
+    //
+    pub fn insert(&mut self, digest: &[u8]) -> bool {
+        if self.contains(digest) {
+            return false;
+        }
+        Arc::make_mut(&mut self.delta).removed.remove(digest);
+        if !self.contains(digest) {
+            Arc::make_mut(&mut self.delta).added.insert(digest);
+        }
+        true
+    }
+
+    //
+    // Returns false if the digest does not exist.
+    //
+    pub fn remove(&mut self, digest: &[u8]) -> bool {
+        if !self.contains(digest) {
+            return false;
+        }
+        Arc::make_mut(&mut self.delta).added.remove(digest);
+        if self.contains(digest) {
+            Arc::make_mut(&mut self.delta).removed.insert(digest);
+        }
+        true
+    }
+}
+
+//
+// Sorted, disjoint hashes added & removed relative to the layers below.
+//
+#[derive(Clone)]
+struct Delta {
+    added: HashTable,
+    removed: HashTable,
+}
+
+impl Delta {
+    fn new(algorithm: HashAlgorithm) -> Self {
+        Delta {
+            added: HashTable::new(algorithm),
+            removed: HashTable::new(algorithm),
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.added.len() + self.removed.len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty()
+    }
+
+    //
+    // Some(true) if added, Some(false) if removed & None if unchanged.
+    //
+    fn get(&self, digest: &[u8]) -> Option<bool> {
+        if self.added.binary_search(digest).is_ok() {
+            Some(true)
+        } else if self.removed.binary_search(digest).is_ok() {
+            Some(false)
+        } else {
+            None
+        }
+    }
+}
+
+//
+// Sorted table of digests of a single algorithm.
+// Digests are packed back to back in a single buffer, `digest_size` bytes
+// each, which keeps the memory footprint equal to the raw digest size.
+// The buffer is either owned or a read-only map of a binary index, which is
+// copied to the heap on the first modification.
+//
+pub struct HashTable {
+    algorithm: HashAlgorithm,
+    storage: Storage,
+}
+
+// Clones are always owned
+impl Clone for HashTable {
+    fn clone(&self) -> Self {
+        HashTable::from_packed(self.algorithm, self.as_bytes().to_vec())
+    }
+}
+
+enum Storage {
+    Heap(Vec<u8>),
+    Mapped { map: Mmap, offset: usize },
+}
+
+impl HashTable {
+    pub fn new(algorithm: HashAlgorithm) -> Self {
+        Self::with_capacity(algorithm, 0)
+    }
+
+    pub fn with_capacity(algorithm: HashAlgorithm, capacity: usize) -> Self {
+        HashTable {
+            algorithm,
+            storage: Storage::Heap(Vec::with_capacity(capacity * algorithm.digest_size())),
+        }
+    }
+
+    //
+    // Wrap already sorted & unique packed digests.
+    //
+    pub fn from_packed(algorithm: HashAlgorithm, digests: Vec<u8>) -> Self {
+        HashTable { algorithm, storage: Storage::Heap(digests) }
+    }
+
+    //
+    // Wrap already sorted & unique packed digests, starting at `offset` of a map.
+    //
+    pub fn from_mapped(algorithm: HashAlgorithm, map: Mmap, offset: usize) -> Self {
+        HashTable { algorithm, storage: Storage::Mapped { map, offset } }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        match &self.storage {
+            Storage::Heap(digests) => digests,
+            Storage::Mapped { map, offset } => &map.as_slice()[*offset..],
+        }
+    }
+
+    //
+    // Mutable access to the digests, moving mapped digests to the heap.
+    //
+    fn digests_mut(&mut self) -> &mut Vec<u8> {
+        if let Storage::Mapped { map, offset } = &self.storage {
+            warn!("Copying memory mapped {} hashes to the heap for modification.",
+                self.algorithm.name());
+            self.storage = Storage::Heap(map.as_slice()[*offset..].to_vec());
+        }
+        match &mut self.storage {
+            Storage::Heap(digests) => digests,
+            Storage::Mapped { .. } => unreachable!(),
+        }
+    }
+
+    pub fn algorithm(&self) -> HashAlgorithm {
+        self.algorithm
+    }
+
+    pub fn len(&self) -> usize {
+        self.as_bytes().len() / self.algorithm.digest_size()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.as_bytes().is_empty()
+    }
+
+    pub fn get(&self, index: usize) -> &[u8] {
+        let size = self.algorithm.digest_size();
+        &self.as_bytes()[index * size..(index + 1) * size]
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &[u8]> {
+        self.as_bytes().chunks_exact(self.algorithm.digest_size())
+    }
+
+    //
+    // Append a digest, caller is responsible for keeping the order.
+    //
+    pub fn push(&mut self, digest: &[u8]) {
+        self.digests_mut().extend_from_slice(digest);
+    }
+
+    //
+    // Same semantics as `slice::binary_search`.
+    //
+    pub fn binary_search(&self, digest: &[u8]) -> Result<usize, usize> {
+        let mut low = 0;
+        let mut high = self.len();
+        while low < high {
+            let middle = low + (high - low) / 2;
+            match self.get(middle).cmp(digest) {
+                Ordering::Less => low = middle + 1,
+                Ordering::Greater => high = middle,
+                Ordering::Equal => return Ok(middle),
+            }
+        }
+        Err(low)
+    }
+
+    //
+    // Sort & remove duplicate digests, for input that was pushed unordered.
+    //
+    pub fn sort_and_dedup(&mut self) {
+        let size = self.algorithm.digest_size();
+        let digests = self.digests_mut();
+        match size {
+            16 => parallel_sort(digests.as_chunks_mut::<16>().0),
+            20 => parallel_sort(digests.as_chunks_mut::<20>().0),
+            32 => parallel_sort(digests.as_chunks_mut::<32>().0),
+            size => unreachable!("Unsupported digest size {}.", size),
+        }
+        self.dedup();
+    }
+
+    //
+    // Remove consecutive duplicate digests, keeping the first of each run.
+    //
+    pub fn dedup(&mut self) {
+        let size = self.algorithm.digest_size();
+        let count = self.len();
+        let digests = self.digests_mut();
+        let mut unique = 0;
+        for i in 0..count {
+            let offset = i * size;
+            if i == 0 || digests[offset..offset + size] != digests[(unique - 1) * size..unique * size] {
+                digests.copy_within(offset..offset + size, unique * size);
+                unique += 1;
+            }
+        }
+        digests.truncate(unique * size);
+    }
+
+    //
+    // Insert a digest at its sorted position.
+    // Returns false if the digest already exists.
+    //
+    pub fn insert(&mut self, digest: &[u8]) -> bool {
+        match self.binary_search(digest) {
+            Ok(_) => false,
+            Err(pos) => {
+                let offset = pos * self.algorithm.digest_size();
+                self.digests_mut().splice(offset..offset, digest.iter().copied());
+                true
+            }
+        }
+    }
+
+    //
+    // New table with the sorted `added` digests inserted & the sorted
+    // `removed` digests left out, in a single pass over all three.
+    // Returns the table & the number of digests actually inserted & removed.
+    //
+    pub fn apply(&self, added: &HashTable, removed: &HashTable) -> (HashTable, usize, usize) {
+        let mut table = HashTable::with_capacity(self.algorithm, self.len() + added.len());
+        let mut base = self.iter().peekable();
+        let mut added = added.iter().peekable();
+        let mut removed = removed.iter().peekable();
+        let mut inserted_count = 0;
+        let mut removed_count = 0;
+
+        loop {
+            let (digest, new) = match (base.peek(), added.peek()) {
+                (Some(old), Some(new)) => match old.cmp(new) {
+                    Ordering::Less => (base.next(), false),
+                    Ordering::Greater => (added.next(), true),
+                    Ordering::Equal => {
+                        base.next();
+                        (added.next(), false)
+                    },
+                },
+                (Some(_), None) => (base.next(), false),
+                (None, Some(_)) => (added.next(), true),
+                (None, None) => break,
+            };
+            let digest = digest.unwrap();
+
+            while removed.next_if(|removed| *removed < digest).is_some() {}
+            if removed.next_if(|removed| *removed == digest).is_some() {
+                removed_count += 1;
+                continue;
+            }
+
+            inserted_count += new as usize;
+            table.push(digest);
+        }
+
+        (table, inserted_count, removed_count)
+    }
+
+    //
+    // Remove a digest, keeping the order.
+    // Returns false if the digest does not exist.
+    //
+    pub fn remove(&mut self, digest: &[u8]) -> bool {
+        match self.binary_search(digest) {
+            Ok(pos) => {
+                let size = self.algorithm.digest_size();
+                self.digests_mut().drain(pos * size..(pos + 1) * size);
+                true
+            },
+            Err(_) => false,
+        }
+    }
+}
+
+// Below this size a single threaded sort is faster than spawning threads
+const PARALLEL_SORT_THRESHOLD: usize = 1 << 20;
+
+//
+// Sort runs of the slice on all cores, then merge the runs pairwise.
+// Needs a scratch copy of the slice, so peak memory is doubled while sorting.
+//
+fn parallel_sort<T: Ord + Copy + Send + Sync>(items: &mut [T]) {
+    let threads = thread::available_parallelism().map_or(1, |n| n.get());
+    if threads < 2 || items.len() < PARALLEL_SORT_THRESHOLD {
+        items.sort_unstable();
+        return;
+    }
+
+    let run_size = items.len().div_ceil(threads);
+    thread::scope(|scope| {
+        for run in items.chunks_mut(run_size) {
+            scope.spawn(move || run.sort_unstable());
+        }
+    });
+
+    let mut buffer: Vec<T> = items.to_vec();
+    let mut sorted_in_items = true;
+    let mut width = run_size;
+
+    while width < items.len() {
+        let (source, target): (&[T], &mut [T]) = if sorted_in_items {
+            (items, &mut buffer)
+        } else {
+            (&buffer, items)
+        };
+
+        thread::scope(|scope| {
+            let mut target = target;
+            let mut start = 0;
+            while start < source.len() {
+                let middle = (start + width).min(source.len());
+                let end = (start + 2 * width).min(source.len());
+                let (output, rest) = target.split_at_mut(end - start);
+                target = rest;
+                let (left, right) = (&source[start..middle], &source[middle..end]);
+                scope.spawn(move || merge(left, right, output));
+                start = end;
+            }
+        });
+
+        sorted_in_items = !sorted_in_items;
+        width *= 2;
+    }
+
+    if !sorted_in_items {
+        items.copy_from_slice(&buffer);
+    }
+}
+
+fn merge<T: Ord + Copy>(left: &[T], right: &[T], output: &mut [T]) {
+    let (mut i, mut j) = (0, 0);
+    for slot in output.iter_mut() {
+        if j >= right.len() || (i < left.len() && left[i] <= right[j]) {
+            *slot = left[i];
+            i += 1;
+        } else {
+            *slot = right[j];
+            j += 1;
+        }
+    }
+}
@@ -1,5 +1,6 @@
 pub const VERSION: &str = "0.1.0";
 pub const LOG_LEVELS: [&str; 5] = ["info", "warn", "error", "debug", "trace"];
+pub const HASH_TYPES: [&str; 3] = ["md5", "sha1", "sha256"];
 pub const BANNER: &str = r#"
      __     __            __   __              
  ___/ /_ __/ /  ___ ____ / /  / /____ _____  __
@@ -7,3 +8,11 @@ pub const BANNER: &str = r#"
 \_,_/\_,_/_//_/\_,_/___/_//_/\__/___/_/  |___/
 "#;
 pub const CHANGE_FILE_DIR: &str = ".change-files";
+pub const SNAPSHOT_DIR: &str = ".snapshots";
+pub const DATA_BASE_DIR: &str = "base";
+pub const DATA_CHANGE_FILE_DIR: &str = "change-files";
+pub const DATA_BACKUP_DIR: &str = "backups";
+pub const DATA_SNAPSHOT_DIR: &str = "snapshots";
+pub const DATA_LOCK_FILE: &str = "lock";
+pub const DEFAULT_SET_NAME: &str = "default";
+pub const BACKUP_COUNT: usize = 5;
@@ -0,0 +1,120 @@
+use anyhow::{Result, bail};
+
+//
+// Supported hash algorithms.
+// Digests are stored as raw big-endian bytes, so the byte-wise ordering
+// matches the ordering of the uppercase hex representation.
+//
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum HashAlgorithm {
+    Md5,
+    Sha1,
+    Sha256,
+}
+
+pub const MAX_DIGEST_SIZE: usize = 32;
+
+impl HashAlgorithm {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "md5" => Some(Self::Md5),
+            "sha1" => Some(Self::Sha1),
+            "sha256" => Some(Self::Sha256),
+            _ => None,
+        }
+    }
+
+    //
+    // Hex lengths are unique per algorithm, which allows detecting the
+    // algorithm of a line without any extra markers.
+    //
+    pub fn from_hex_size(size: usize) -> Option<Self> {
+        match size {
+            32 => Some(Self::Md5),
+            40 => Some(Self::Sha1),
+            64 => Some(Self::Sha256),
+            _ => None,
+        }
+    }
+
+    //
+    // Single byte id used by the binary index & change file formats.
+    //
+    pub fn from_id(id: u8) -> Option<Self> {
+        match id {
+            1 => Some(Self::Md5),
+            2 => Some(Self::Sha1),
+            3 => Some(Self::Sha256),
+            _ => None,
+        }
+    }
+
+    pub fn id(&self) -> u8 {
+        match self {
+            Self::Md5 => 1,
+            Self::Sha1 => 2,
+            Self::Sha256 => 3,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Md5 => "md5",
+            Self::Sha1 => "sha1",
+            Self::Sha256 => "sha256",
+        }
+    }
+
+    pub fn digest_size(&self) -> usize {
+        match self {
+            Self::Md5 => 16,
+            Self::Sha1 => 20,
+            Self::Sha256 => 32,
+        }
+    }
+
+    pub fn hex_size(&self) -> usize {
+        self.digest_size() * 2
+    }
+}
+
+//
+// Parse a hex line into `out`, which must be `digest_size` bytes long.
+//
+pub fn parse_hash(algorithm: HashAlgorithm, line: &str, out: &mut [u8]) -> Result<()> {
+    if line.len() != algorithm.hex_size() {
+        bail!("Got invalid {} hash \"{}\", size != {} bytes.",
+            algorithm.name(), line, algorithm.hex_size());
+    }
+
+    let bytes = line.as_bytes();
+    for (i, byte) in out.iter_mut().enumerate() {
+        let high = hex_value(bytes[i * 2]);
+        let low = hex_value(bytes[i * 2 + 1]);
+        match (high, low) {
+            (Some(high), Some(low)) => *byte = (high << 4) | low,
+            _ => bail!("Failed to parse \"{}\" as {} hash.", line, algorithm.name()),
+        }
+    }
+
+    Ok(())
+}
+
+pub fn format_hash(digest: &[u8]) -> String {
+    const HEX: &[u8; 16] = b"0123456789ABCDEF";
+    let mut hex = String::with_capacity(digest.len() * 2);
+    for byte in digest {
+        hex.push(HEX[(byte >> 4) as usize] as char);
+        hex.push(HEX[(byte & 0x0F) as usize] as char);
+    }
+    hex
+}
+
+fn hex_value(byte: u8) -> Option<u8> {
+    match byte {
+        b'0'..=b'9' => Some(byte - b'0'),
+        b'a'..=b'f' => Some(byte - b'a' + 10),
+        b'A'..=b'F' => Some(byte - b'A' + 10),
+        _ => None,
+    }
+}
@@ -0,0 +1,381 @@
+use anyhow::{Result, bail};
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Instant;
+
+use log::{info, error};
+
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::net::tcp::ReadHalf;
+
+use crate::database::Snapshot;
+use crate::globals;
+use crate::hash::{self, HashAlgorithm, MAX_DIGEST_SIZE};
+use crate::proto::{self, HashDatabase};
+
+//
+// HTTP/1.1 JSON API on its own port (`--http-port`), for tools that can't
+// speak the binary protocol. Connections are kept alive unless the client
+// asks otherwise, request bodies need a Content-Length.
+//
+//   POST /v1/query       ["<hex>", ...]  ->  [true, false, ...]
+//   POST /v1/update      ["<hex>", ...]  ->  {"inserted": N}
+//   POST /v1/delete      ["<hex>", ...]  ->  {"removed": N}
+//   GET  /v1/hash/<hex>                  ->  {"hash": "<hex>", "found": true,
+//                                             "sets": [{"name": "default",
+//                                                       "category": "known-good"}]}
+//   GET  /health                         ->  {"status": "ok", "version": "...",
+//                                             "follower": false}
+//
+// Hashes are hex MD5, SHA-1 or SHA-256 & may be mixed in one request.
+// Queries, updates & deletions apply to the default set, updates & deletions
+// are logged to change files just like those of the binary protocol. The
+// hash lookup checks every named set.
+//
+// Errors are answered with a 4xx or 5xx status & {"error": "<message>"}.
+//
+
+const MAX_HEADER_SIZE: u64 = 1 << 16;
+const MAX_BODY_SIZE: usize = 1 << 26;
+
+struct Request {
+    method: String,
+    path: String,
+    body: Vec<u8>,
+    close: bool,
+}
+
+struct Response {
+    status: u16,
+    body: String,
+}
+
+impl Response {
+    fn ok(body: String) -> Self {
+        Response { status: 200, body }
+    }
+
+    fn error(status: u16, message: &str) -> Self {
+        Response { status, body: format!("{{\"error\": {}}}", json_string(message)) }
+    }
+}
+
+pub async fn handle_client(socket: &mut TcpStream, hashes: &HashDatabase) {
+    match handle_connection(socket, hashes).await {
+        Ok(_) => {},
+        Err(error) => {
+            error!("Failed to serve HTTP client.");
+            error!("{}", error);
+        }
+    };
+    match socket.shutdown().await {
+        Ok(_) => {},
+        Err(error) => {
+            error!("Failed to close connection.");
+            error!("{}", error);
+        }
+    };
+}
+
+async fn handle_connection(socket: &mut TcpStream, hashes: &HashDatabase) -> Result<()> {
+    let (reader, mut writer) = socket.split();
+    let mut reader = BufReader::new(reader);
+
+    loop {
+        let (request, close) = match read_request(&mut reader, &mut writer).await {
+            Ok(Some(request)) => {
+                let close = request.close;
+                (Ok(request), close)
+            },
+            Ok(None) => break,
+            Err(error) => (Err(error), true),
+        };
+
+        let response = match request {
+            Ok(request) => {
+                info!("Received HTTP request \"{} {}\".", request.method, request.path);
+                route(hashes, &request).await
+            },
+            Err(error) => {
+                error!("Received an invalid HTTP request.");
+                error!("{}", error);
+                Response::error(400, &error.to_string())
+            },
+        };
+
+        let message = format!(
+            "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: {}\r\n\r\n{}\n",
+            response.status, reason(response.status), response.body.len() + 1,
+            if close { "close" } else { "keep-alive" }, response.body);
+        writer.write_all(message.as_bytes()).await?;
+
+        if close {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+//
+// Read the next request, None if the client closed the connection between
+// requests.
+//
+async fn read_request(
+    reader: &mut BufReader<ReadHalf<'_>>,
+    writer: &mut (impl AsyncWriteExt + Unpin),
+) -> Result<Option<Request>> {
+    let mut head_size = 0;
+    let mut lines: Vec<String> = vec![];
+    loop {
+        let mut line = vec![];
+        let limit = MAX_HEADER_SIZE.saturating_sub(head_size);
+        if (&mut *reader).take(limit).read_until(b'\n', &mut line).await? == 0 {
+            match lines.is_empty() {
+                true => return Ok(None),
+                false => bail!("Connection closed within the request header."),
+            }
+        }
+        if !line.ends_with(b"\n") {
+            bail!("Request header is larger than {} bytes.", MAX_HEADER_SIZE);
+        }
+        head_size += line.len() as u64;
+
+        let line = String::from_utf8_lossy(&line).trim_end().to_owned();
+        if line.is_empty() {
+            // Empty lines before the request line are allowed
+            if lines.is_empty() {
+                continue;
+            }
+            break;
+        }
+        lines.push(line);
+    }
+
+    let (method, path, version) = match lines[0].split(' ').collect::<Vec<&str>>()[..] {
+        [method, path, version] => (method.to_owned(), path.to_owned(), version.to_owned()),
+        _ => bail!("Got invalid request line \"{}\".", lines[0]),
+    };
+
+    let mut content_length = 0;
+    let mut close = version == "HTTP/1.0";
+    let mut expect_continue = false;
+    for line in &lines[1..] {
+        let (name, value) = match line.split_once(':') {
+            Some((name, value)) => (name.trim().to_ascii_lowercase(), value.trim()),
+            None => bail!("Got invalid header \"{}\".", line),
+        };
+        match name.as_str() {
+            "content-length" => match value.parse::<usize>() {
+                Ok(length) => content_length = length,
+                Err(_) => bail!("Got invalid content length \"{}\".", value),
+            },
+            "connection" if value.eq_ignore_ascii_case("close") => close = true,
+            "connection" if value.eq_ignore_ascii_case("keep-alive") => close = false,
+            "transfer-encoding" => bail!("Transfer encodings are not supported, send a content length."),
+            "expect" if value.eq_ignore_ascii_case("100-continue") => expect_continue = true,
+            _ => {},
+        }
+    }
+
+    if content_length > MAX_BODY_SIZE {
+        bail!("Request body is larger than {} bytes.", MAX_BODY_SIZE);
+    }
+    if expect_continue && content_length > 0 {
+        writer.write_all(b"HTTP/1.1 100 Continue\r\n\r\n").await?;
+    }
+
+    let mut body = vec![0u8; content_length];
+    reader.read_exact(&mut body).await?;
+
+    // The query string carries nothing
+    let path = match path.split_once('?') {
+        Some((path, _)) => path.to_owned(),
+        None => path,
+    };
+
+    Ok(Some(Request { method, path, body, close }))
+}
+
+async fn route(hashes: &HashDatabase, request: &Request) -> Response {
+    let now = Instant::now();
+
+    let response = match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/health") => health(hashes),
+        ("POST", "/v1/query") => query(hashes, &request.body),
+        ("POST", "/v1/update") => update(hashes, &request.body, true).await,
+        ("POST", "/v1/delete") => update(hashes, &request.body, false).await,
+        ("GET", path) if path.starts_with("/v1/hash/") => lookup(hashes, &path["/v1/hash/".len()..]),
+        (_, "/health" | "/v1/query" | "/v1/update" | "/v1/delete") => {
+            Response::error(405, "Method not allowed.")
+        },
+        (_, path) if path.starts_with("/v1/hash/") => Response::error(405, "Method not allowed."),
+        _ => Response::error(404, "Not found."),
+    };
+
+    info!("Total time taken: {:.2?}.", now.elapsed());
+
+    response
+}
+
+fn health(hashes: &HashDatabase) -> Response {
+    Response::ok(format!("{{\"status\": \"ok\", \"version\": {}, \"follower\": {}}}",
+        json_string(globals::VERSION), hashes.is_read_only()))
+}
+
+fn query(hashes: &HashDatabase, body: &[u8]) -> Response {
+    let digests = match parse_hashes(hashes, body) {
+        Ok(digests) => digests,
+        Err(error) => return Response::error(400, &error.to_string()),
+    };
+
+    info!("Received an HTTP query with {} hashes.", digests.len());
+
+    let mut snapshots: HashMap<HashAlgorithm, Arc<Snapshot>> = HashMap::new();
+    let mut results = vec![];
+    for (algorithm, digest) in &digests {
+        let table = snapshots.entry(*algorithm)
+            .or_insert_with(|| hashes.get(*algorithm).unwrap().snapshot());
+        results.push(if table.contains(digest) { "true" } else { "false" });
+    }
+
+    Response::ok(format!("[{}]", results.join(", ")))
+}
+
+async fn update(hashes: &HashDatabase, body: &[u8], insert: bool) -> Response {
+    if hashes.is_read_only() {
+        error!("Received an HTTP update or delete on a follower.");
+        return Response::error(403, "Updates & deletions are rejected by followers.");
+    }
+
+    let digests = match parse_hashes(hashes, body) {
+        Ok(digests) => digests,
+        Err(error) => return Response::error(400, &error.to_string()),
+    };
+
+    info!("Received an HTTP {} with {} hashes.",
+        if insert { "update" } else { "delete" }, digests.len());
+
+    // One change file per algorithm
+    let mut packed: Vec<(HashAlgorithm, Vec<u8>)> = vec![];
+    for (algorithm, digest) in &digests {
+        match packed.iter_mut().find(|(packed_algorithm, _)| packed_algorithm == algorithm) {
+            Some((_, bytes)) => bytes.extend_from_slice(digest),
+            None => packed.push((*algorithm, digest.clone())),
+        }
+    }
+
+    let mut changed = 0;
+    for (algorithm, bytes) in &packed {
+        match proto::update_table(hashes, *algorithm, bytes, insert).await {
+            Ok(count) => changed += count,
+            Err(error) => {
+                error!("Failed to apply HTTP changes.");
+                error!("{}", error);
+                return Response::error(500, &error.to_string());
+            }
+        }
+    }
+
+    info!("{} a total of {}/{} hashes.",
+        if insert { "Inserted" } else { "Removed" }, changed, digests.len());
+
+    let key = if insert { "inserted" } else { "removed" };
+    Response::ok(format!("{{\"{}\": {}}}", key, changed))
+}
+
+fn lookup(hashes: &HashDatabase, hex: &str) -> Response {
+    let (algorithm, digest) = match parse_hash(hex) {
+        Ok(hash) => hash,
+        Err(error) => return Response::error(400, &error.to_string()),
+    };
+    if hashes.sets().iter().all(|set| set.get(algorithm).is_none()) {
+        return Response::error(400, &format!("No {} hashes are served.", algorithm.name()));
+    }
+
+    let mut sets = vec![];
+    for set in hashes.sets() {
+        if set.get(algorithm).is_some_and(|table| table.snapshot().contains(&digest)) {
+            sets.push(format!("{{\"name\": {}, \"category\": \"{}\"}}",
+                json_string(set.name()), set.category().name()));
+        }
+    }
+    let found = hashes.get(algorithm).is_some_and(|table| table.snapshot().contains(&digest));
+
+    Response::ok(format!("{{\"hash\": \"{}\", \"found\": {}, \"sets\": [{}]}}",
+        hash::format_hash(&digest), found, sets.join(", ")))
+}
+
+//
+// Parse a JSON array of hex hash strings of algorithms the default set
+// serves. Hashes hold no escapes, so neither may the strings.
+//
+fn parse_hashes(hashes: &HashDatabase, body: &[u8]) -> Result<Vec<(HashAlgorithm, Vec<u8>)>> {
+    let body = match std::str::from_utf8(body) {
+        Ok(body) => body.trim(),
+        Err(_) => bail!("Request body is not UTF-8."),
+    };
+    let items = match body.strip_prefix('[').and_then(|body| body.strip_suffix(']')) {
+        Some(items) => items.trim(),
+        None => bail!("Request body is not a JSON array of hex hashes."),
+    };
+    if items.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let mut digests = vec![];
+    for item in items.split(',') {
+        let item = item.trim();
+        let hex = match item.strip_prefix('"').and_then(|item| item.strip_suffix('"')) {
+            Some(hex) if !hex.contains(['"', '\\']) => hex,
+            _ => bail!("Got invalid JSON string {}.", item),
+        };
+        let (algorithm, digest) = parse_hash(hex)?;
+        if hashes.get(algorithm).is_none() {
+            bail!("No {} hashes are served.", algorithm.name());
+        }
+        digests.push((algorithm, digest));
+    }
+    Ok(digests)
+}
+
+fn parse_hash(hex: &str) -> Result<(HashAlgorithm, Vec<u8>)> {
+    let algorithm = match HashAlgorithm::from_hex_size(hex.len()) {
+        Some(algorithm) => algorithm,
+        None => bail!("Got invalid hash \"{}\".", hex),
+    };
+
+    let mut buffer = [0u8; MAX_DIGEST_SIZE];
+    let digest = &mut buffer[..algorithm.digest_size()];
+    hash::parse_hash(algorithm, hex, digest)?;
+    Ok((algorithm, digest.to_vec()))
+}
+
+fn json_string(text: &str) -> String {
+    let mut quoted = String::from("\"");
+    for character in text.chars() {
+        match character {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            character if (character as u32) < 0x20 => {
+                quoted.push_str(&format!("\\u{:04x}", character as u32))
+            },
+            character => quoted.push(character),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        _ => "Internal Server Error",
+    }
+}
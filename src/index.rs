@@ -0,0 +1,155 @@
+use anyhow::{Result, bail};
+
+use std::fs::File;
+use std::io::{BufWriter, Read, Write};
+use std::path::Path;
+
+use log::info;
+
+use crate::database::HashTable;
+use crate::hash::HashAlgorithm;
+use crate::mmap::Mmap;
+use crate::utils;
+
+//
+// Binary on-disk index, loaded with a single read instead of parsing hex.
+//
+// +---------+---------+-----------+----------+---------+----------+----------+
+// |  Magic  | Version | Algorithm | Reserved |  Count  | Checksum | Reserved |
+// +---------+---------+-----------+----------+---------+----------+----------+
+// | 8 bytes | u16be   | 1 byte    | 5 bytes  | u64be   | u32be    | 4 bytes  |
+// +---------+---------+-----------+----------+---------+----------+----------+
+//
+// Followed by `Count` sorted, unique, packed big-endian digests.
+// The checksum is the CRC-32 of the digest data.
+//
+
+pub const INDEX_MAGIC: &[u8; 8] = b"DUHASHIX";
+pub const INDEX_HEADER_SIZE: usize = 32;
+const INDEX_VERSION: u16 = 1;
+
+pub struct IndexHeader {
+    pub algorithm: HashAlgorithm,
+    pub count: u64,
+    pub checksum: u32,
+}
+
+impl IndexHeader {
+    fn to_bytes(&self) -> [u8; INDEX_HEADER_SIZE] {
+        let mut header = [0u8; INDEX_HEADER_SIZE];
+        header[..8].copy_from_slice(INDEX_MAGIC);
+        header[8..10].copy_from_slice(&INDEX_VERSION.to_be_bytes());
+        header[10] = self.algorithm.id();
+        header[16..24].copy_from_slice(&self.count.to_be_bytes());
+        header[24..28].copy_from_slice(&self.checksum.to_be_bytes());
+        header
+    }
+
+    pub fn from_bytes(header: &[u8; INDEX_HEADER_SIZE]) -> Result<Self> {
+        if &header[..8] != INDEX_MAGIC {
+            bail!("Not a hash index file.");
+        }
+
+        let version = u16::from_be_bytes([header[8], header[9]]);
+        if version != INDEX_VERSION {
+            bail!("Unsupported hash index version {}.", version);
+        }
+
+        let algorithm = match HashAlgorithm::from_id(header[10]) {
+            Some(algorithm) => algorithm,
+            None => bail!("Got invalid hash index algorithm {}.", header[10]),
+        };
+
+        Ok(IndexHeader {
+            algorithm,
+            count: u64::from_be_bytes(header[16..24].try_into()?),
+            checksum: u32::from_be_bytes(header[24..28].try_into()?),
+        })
+    }
+}
+
+//
+// Read an index in memory, verifying the algorithm, size & checksum.
+//
+pub fn read_index<P>(algorithm: HashAlgorithm, path: P) -> Result<HashTable>
+where P: AsRef<Path>, {
+    let mut file = File::open(&path)?;
+
+    let mut header = [0u8; INDEX_HEADER_SIZE];
+    file.read_exact(&mut header)?;
+    let header = IndexHeader::from_bytes(&header)?;
+
+    if header.algorithm != algorithm {
+        bail!("Hash index holds {} hashes, expected {}.",
+            header.algorithm.name(), algorithm.name());
+    }
+
+    let size = header.count as usize * algorithm.digest_size();
+    if utils::get_size(&path)? != (INDEX_HEADER_SIZE + size) as u64 {
+        bail!("Hash index size does not match {} {} hashes.", header.count, algorithm.name());
+    }
+
+    info!("Reading binary index of {} {} hashes.", header.count, algorithm.name());
+
+    let mut digests = vec![0u8; size];
+    file.read_exact(&mut digests)?;
+
+    if utils::crc32(0, &digests) != header.checksum {
+        bail!("Hash index checksum mismatch.");
+    }
+
+    Ok(HashTable::from_packed(algorithm, digests))
+}
+
+//
+// Map an index read-only instead of reading it, queries then binary search
+// the mapped file directly. Only the header & size are verified, as the
+// checksum would have to touch every page.
+//
+pub fn map_index<P>(algorithm: HashAlgorithm, path: P) -> Result<HashTable>
+where P: AsRef<Path>, {
+    let file = File::open(&path)?;
+    let map = Mmap::map(&file)?;
+
+    let header = match map.as_slice().first_chunk::<INDEX_HEADER_SIZE>() {
+        Some(header) => IndexHeader::from_bytes(header)?,
+        None => bail!("Got truncated hash index header."),
+    };
+
+    if header.algorithm != algorithm {
+        bail!("Hash index holds {} hashes, expected {}.",
+            header.algorithm.name(), algorithm.name());
+    }
+
+    let size = header.count as usize * algorithm.digest_size();
+    if map.as_slice().len() != INDEX_HEADER_SIZE + size {
+        bail!("Hash index size does not match {} {} hashes.", header.count, algorithm.name());
+    }
+
+    info!("Mapped binary index of {} {} hashes, checksum not verified.",
+        header.count, algorithm.name());
+
+    Ok(HashTable::from_mapped(algorithm, map, INDEX_HEADER_SIZE))
+}
+
+//
+// Write a table as an index, replacing any existing file.
+//
+pub fn write_index<P>(path: P, table: &HashTable) -> Result<()>
+where P: AsRef<Path>, {
+    let header = IndexHeader {
+        algorithm: table.algorithm(),
+        count: table.len() as u64,
+        checksum: utils::crc32(0, table.as_bytes()),
+    };
+
+    let file = File::create(&path)?;
+    let mut writer = BufWriter::new(file);
+    writer.write_all(&header.to_bytes())?;
+    writer.write_all(table.as_bytes())?;
+
+    let file = writer.into_inner()?;
+    file.sync_all()?;
+
+    Ok(())
+}
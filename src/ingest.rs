@@ -0,0 +1,327 @@
+use anyhow::{Result, bail};
+
+use std::cmp::Ordering;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read};
+use std::path::Path;
+use std::time::Instant;
+
+use log::{info, warn};
+
+use crate::database::HashTable;
+use crate::hash::{self, HashAlgorithm, MAX_DIGEST_SIZE};
+use crate::index::{self, INDEX_MAGIC};
+use crate::sqlite::{self, SQLITE_MAGIC};
+use crate::utils;
+
+//
+// On-disk hash file formats, detected from the start of the file.
+//
+enum HashFileFormat {
+    // One hex digest per line
+    Text,
+    // NSRL RDS 2.x `NSRLFile.txt` quoted CSV
+    RdsCsv,
+    // NSRL RDSv3 SQLite release
+    RdsSqlite,
+    // Binary index, see `index.rs`
+    Binary,
+}
+
+// NSRL RDSv3 table & columns holding the digests
+const RDS_FILE_TABLE: &str = "FILE";
+// Start of the NSRL RDS 2.x CSV header line
+const RDS_CSV_HEADER: &[u8] = b"\"SHA-1\",\"MD5\"";
+
+fn detect_format<P>(path: P) -> Result<HashFileFormat>
+where P: AsRef<Path>, {
+    let mut header = [0u8; 16];
+    let mut file = File::open(path)?;
+    let mut size = 0;
+    while size < header.len() {
+        match file.read(&mut header[size..])? {
+            0 => break,
+            n => size += n,
+        }
+    }
+
+    if &header[..size] == SQLITE_MAGIC {
+        return Ok(HashFileFormat::RdsSqlite);
+    }
+    if header[..size].starts_with(INDEX_MAGIC) {
+        return Ok(HashFileFormat::Binary);
+    }
+    if header[..size].starts_with(RDS_CSV_HEADER) {
+        return Ok(HashFileFormat::RdsCsv);
+    }
+    Ok(HashFileFormat::Text)
+}
+
+//
+// A hash file given on the command line, `[algorithm:]path`.
+//
+#[derive(Clone)]
+pub struct HashFile {
+    pub algorithm: HashAlgorithm,
+    pub path: String,
+}
+
+#[derive(Clone, Copy, Default)]
+pub struct ReadOptions {
+    // Refuse unsorted text hash files instead of sorting them
+    pub require_sorted: bool,
+    // Memory map binary indexes instead of reading them
+    pub mmap: bool,
+}
+
+//
+// Read a hash file of any supported format into a sorted table.
+//
+pub fn read_hash_file<P>(algorithm: HashAlgorithm, path: P, options: &ReadOptions) -> Result<HashTable>
+where P: AsRef<Path>, {
+    let format = detect_format(&path)?;
+    if options.mmap && !matches!(format, HashFileFormat::Binary) {
+        warn!("Only binary indexes can be memory mapped, reading in memory instead.");
+    }
+
+    match format {
+        HashFileFormat::Text => read_text(algorithm, path, options.require_sorted),
+        HashFileFormat::RdsCsv => read_rds_csv(algorithm, path),
+        HashFileFormat::RdsSqlite => read_rds_sqlite(algorithm, path),
+        HashFileFormat::Binary if options.mmap => index::map_index(algorithm, path),
+        HashFileFormat::Binary => index::read_index(algorithm, path),
+    }
+}
+
+//
+// Only text hash files & binary indexes can be rewritten by a merge.
+//
+pub fn is_text<P>(path: P) -> Result<bool>
+where P: AsRef<Path>, {
+    Ok(matches!(detect_format(path)?, HashFileFormat::Text))
+}
+
+pub fn is_binary<P>(path: P) -> Result<bool>
+where P: AsRef<Path>, {
+    Ok(matches!(detect_format(path)?, HashFileFormat::Binary))
+}
+
+//
+// Strip comments & surrounding whitespace (including CR of CRLF endings).
+// Returns None for lines without a hash.
+//
+pub fn clean_line(line: &str) -> Option<&str> {
+    let line = match line.split_once('#') {
+        Some((hash, _)) => hash,
+        None => line,
+    }.trim();
+
+    if line.is_empty() { None } else { Some(line) }
+}
+
+//
+// Read a text hash file in memory, one hex digest per line.
+// Any case is accepted, blank lines & `#` comments are skipped.
+// Unsorted input is sorted, unless `require_sorted` is set in which case it
+// is refused, as every binary search would be wrong otherwise. Duplicates are
+// removed either way.
+//
+fn read_text<P>(algorithm: HashAlgorithm, path: P, require_sorted: bool) -> Result<HashTable>
+where P: AsRef<Path>, {
+    let ingest_size: u64 = utils::get_size(&path)?;
+    let line_amount: usize = ingest_size as usize / (algorithm.hex_size() + 1);
+
+    info!("Got ingest size: {} bytes.", ingest_size);
+    info!("Calculated total: {} {} hashes.", line_amount, algorithm.name());
+
+    let mut table = HashTable::with_capacity(algorithm, line_amount);
+    let mut buffer = [0u8; MAX_DIGEST_SIZE];
+    let digest = &mut buffer[..algorithm.digest_size()];
+    let mut previous = [0u8; MAX_DIGEST_SIZE];
+    let previous = &mut previous[..algorithm.digest_size()];
+
+    let mut sorted = true;
+    let mut duplicates = false;
+    let mut skipped: usize = 0;
+
+    for (number, line) in utils::read_lines(&path)?.enumerate() {
+        let line = line?;
+        let line = match clean_line(&line) {
+            Some(line) => line,
+            None => {
+                skipped += 1;
+                continue;
+            },
+        };
+
+        hash::parse_hash(algorithm, line, digest)?;
+
+        if !table.is_empty() {
+            match (*previous).cmp(digest) {
+                Ordering::Less => {},
+                Ordering::Equal => duplicates = true,
+                Ordering::Greater => {
+                    if sorted && require_sorted {
+                        bail!("Hash file is not sorted, line {} \"{}\" is out of order.",
+                            number + 1, line);
+                    }
+                    sorted = false;
+                },
+            }
+        }
+        previous.copy_from_slice(digest);
+
+        table.push(digest);
+    }
+
+    if skipped > 0 {
+        info!("Skipped {} blank & comment lines.", skipped);
+    }
+
+    if !sorted {
+        warn!("Hash file is not sorted, sorting {} hashes.", table.len());
+        let now = Instant::now();
+        let count = table.len();
+        table.sort_and_dedup();
+        info!("Sorted & removed {} duplicate hashes in {:.2?}.",
+            count - table.len(), now.elapsed());
+    } else if duplicates {
+        let count = table.len();
+        table.dedup();
+        warn!("Removed {} duplicate hashes.", count - table.len());
+    }
+
+    Ok(table)
+}
+
+//
+// Read the digest column matching `algorithm` from an NSRL RDS 2.x
+// `NSRLFile.txt`. The file holds one row per file & product, so the digests
+// are sorted & deduplicated after reading.
+//
+// "SHA-1","MD5","CRC32","FileName","FileSize","ProductCode","OpSystemCode","SpecialCode"
+//
+fn read_rds_csv<P>(algorithm: HashAlgorithm, path: P) -> Result<HashTable>
+where P: AsRef<Path>, {
+    info!("Reading NSRL RDS 2.x CSV file.");
+
+    let column_name = match algorithm {
+        HashAlgorithm::Md5 => "MD5",
+        HashAlgorithm::Sha1 => "SHA-1",
+        HashAlgorithm::Sha256 => "SHA-256",
+    };
+
+    // File names are not guaranteed to be valid UTF-8, read raw lines
+    let mut lines = BufReader::new(File::open(&path)?).split(b'\n');
+
+    let header = match lines.next() {
+        Some(header) => header?,
+        None => bail!("Got empty NSRL CSV file."),
+    };
+    let column = match split_csv(&header).iter().position(|field| *field == column_name.as_bytes()) {
+        Some(column) => column,
+        None => bail!("NSRL CSV file has no \"{}\" column.", column_name),
+    };
+
+    let ingest_size: u64 = utils::get_size(&path)?;
+    info!("Got ingest size: {} bytes.", ingest_size);
+
+    let mut hashes = HashTable::new(algorithm);
+    let mut buffer = [0u8; MAX_DIGEST_SIZE];
+    let digest = &mut buffer[..algorithm.digest_size()];
+    let mut rows: usize = 0;
+
+    for line in lines {
+        let line = line?;
+        if line.iter().all(u8::is_ascii_whitespace) {
+            continue;
+        }
+        rows += 1;
+
+        let fields = split_csv(&line);
+        let field = match fields.get(column) {
+            Some(field) => String::from_utf8_lossy(field),
+            None => bail!("Got truncated NSRL CSV row {}.", rows),
+        };
+        hash::parse_hash(algorithm, &field, digest)?;
+        hashes.push(digest);
+    }
+
+    info!("Read {} rows, sorting & removing duplicates.", rows);
+    hashes.sort_and_dedup();
+    info!("Got total of {} unique {} hashes.", hashes.len(), algorithm.name());
+
+    Ok(hashes)
+}
+
+//
+// Split a CSV line into unquoted fields.
+// Only the quoting used by NSRL is handled, `""` within quotes is a quote.
+//
+fn split_csv(line: &[u8]) -> Vec<Vec<u8>> {
+    let line = line.strip_suffix(b"\r").unwrap_or(line);
+
+    let mut fields = vec![];
+    let mut field = vec![];
+    let mut quoted = false;
+    let mut i = 0;
+
+    while i < line.len() {
+        match (quoted, line[i]) {
+            (true, b'"') if line.get(i + 1) == Some(&b'"') => {
+                field.push(b'"');
+                i += 1;
+            },
+            (_, b'"') => quoted = !quoted,
+            (false, b',') => fields.push(std::mem::take(&mut field)),
+            (_, byte) => field.push(byte),
+        }
+        i += 1;
+    }
+    fields.push(field);
+    fields
+}
+
+//
+// Read the digest column matching `algorithm` from the `FILE` table of an
+// NSRL RDSv3 release. The table holds one row per file & package, so the
+// digests are sorted & deduplicated after reading.
+//
+fn read_rds_sqlite<P>(algorithm: HashAlgorithm, path: P) -> Result<HashTable>
+where P: AsRef<Path>, {
+    info!("Reading NSRL RDSv3 SQLite database.");
+
+    let database = sqlite::Database::open(&path)?;
+    let table = database.table(RDS_FILE_TABLE)?;
+    let column = match table.column(algorithm.name()) {
+        Some(column) => column,
+        None => bail!("Table \"{}\" has no \"{}\" column.", RDS_FILE_TABLE, algorithm.name()),
+    };
+
+    let mut hashes = HashTable::new(algorithm);
+    let mut buffer = [0u8; MAX_DIGEST_SIZE];
+    let digest = &mut buffer[..algorithm.digest_size()];
+    let mut rows: usize = 0;
+
+    database.scan(&table, |row| {
+        match row[column] {
+            sqlite::Value::Text(text) => {
+                let line = String::from_utf8_lossy(text);
+                hash::parse_hash(algorithm, line.trim(), digest)?;
+                hashes.push(digest);
+            },
+            sqlite::Value::Blob(blob) if blob.len() == digest.len() => hashes.push(blob),
+            // Rows without a digest of this algorithm
+            sqlite::Value::Null => return Ok(()),
+            _ => bail!("Got invalid {} value in row {}.", algorithm.name(), rows + 1),
+        }
+        rows += 1;
+        Ok(())
+    })?;
+
+    info!("Read {} rows, sorting & removing duplicates.", rows);
+    hashes.sort_and_dedup();
+    info!("Got total of {} unique {} hashes.", hashes.len(), algorithm.name());
+
+    Ok(hashes)
+}
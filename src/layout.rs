@@ -0,0 +1,107 @@
+use anyhow::{Result, bail};
+
+use std::fs::{self, File, TryLockError};
+use std::path::{Path, PathBuf};
+
+use log::info;
+
+use crate::globals;
+use crate::hash::HashAlgorithm;
+use crate::ingest::HashFile;
+
+//
+// Where the server keeps its files.
+//
+// With `--data-dir`, everything lives under the data directory:
+//
+// <data-dir>/
+//   lock            held while the server runs
+//   base/<alg>.idx  binary index of each default set algorithm, imported
+//                   from `--hash-file` on the first start & merged into
+//   change-files/   change files, see `wal.rs`
+//   backups/        timestamped backups of base indexes
+//   snapshots/      named snapshots, see `snapshot.rs`
+//
+// Without it, change files go to `globals::CHANGE_FILE_DIR` & snapshots to
+// `globals::SNAPSHOT_DIR` in the working directory, merges rewrite the hash
+// files themselves & backups are kept beside them.
+//
+#[derive(Clone, Default)]
+pub struct Layout {
+    data_dir: Option<PathBuf>,
+}
+
+impl Layout {
+    pub fn new(data_dir: &str) -> Self {
+        match data_dir.is_empty() {
+            true => Layout { data_dir: None },
+            false => Layout { data_dir: Some(PathBuf::from(data_dir)) },
+        }
+    }
+
+    pub fn change_file_dir(&self) -> PathBuf {
+        match &self.data_dir {
+            Some(data_dir) => data_dir.join(globals::DATA_CHANGE_FILE_DIR),
+            None => PathBuf::from(globals::CHANGE_FILE_DIR),
+        }
+    }
+
+    pub fn snapshot_dir(&self) -> PathBuf {
+        match &self.data_dir {
+            Some(data_dir) => data_dir.join(globals::DATA_SNAPSHOT_DIR),
+            None => PathBuf::from(globals::SNAPSHOT_DIR),
+        }
+    }
+
+    //
+    // Base index of an algorithm, None without a data directory.
+    //
+    pub fn base_path(&self, algorithm: HashAlgorithm) -> Option<PathBuf> {
+        self.data_dir.as_ref()
+            .map(|data_dir| data_dir.join(globals::DATA_BASE_DIR).join(algorithm.name().to_owned() + ".idx"))
+    }
+
+    pub fn backup_dir(&self, hash_file: &HashFile) -> PathBuf {
+        match &self.data_dir {
+            Some(data_dir) => data_dir.join(globals::DATA_BACKUP_DIR),
+            None => match Path::new(&hash_file.path).parent() {
+                Some(directory) if !directory.as_os_str().is_empty() => directory.to_owned(),
+                _ => PathBuf::from("."),
+            },
+        }
+    }
+
+    //
+    // Create the data directory layout & lock it.
+    // The returned lock file must be kept open for as long as the server runs.
+    //
+    pub fn create_and_lock(&self) -> Result<Option<File>> {
+        let data_dir = match &self.data_dir {
+            Some(data_dir) => data_dir,
+            None => return Ok(None),
+        };
+
+        for directory in [
+            globals::DATA_BASE_DIR,
+            globals::DATA_CHANGE_FILE_DIR,
+            globals::DATA_BACKUP_DIR,
+            globals::DATA_SNAPSHOT_DIR,
+        ] {
+            fs::create_dir_all(data_dir.join(directory))?;
+        }
+
+        let lock_path = data_dir.join(globals::DATA_LOCK_FILE);
+        let lock = File::options().create(true).truncate(false).write(true).open(&lock_path)?;
+        match lock.try_lock() {
+            Ok(_) => {},
+            Err(TryLockError::WouldBlock) => {
+                bail!("Data directory \"{}\" is in use by another server.", data_dir.display())
+            },
+            Err(TryLockError::Error(error)) => bail!(error),
+        }
+
+        info!("Locked data directory \"{}\".", data_dir.display());
+
+        Ok(Some(lock))
+    }
+}
@@ -7,6 +7,20 @@ mod logger;
 mod globals;
 mod server;
 mod proto;
+mod hash;
+mod database;
+mod ingest;
+mod sqlite;
+mod index;
+mod mmap;
+mod metadata;
+mod merge;
+mod wal;
+mod layout;
+mod snapshot;
+mod replication;
+mod nsrl;
+mod http;
 
 use args::Args;
 
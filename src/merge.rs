@@ -0,0 +1,308 @@
+use anyhow::{Result, bail};
+
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use log::{info, error};
+
+use crate::database::{Database, HashTable};
+use crate::globals;
+use crate::hash::{self, HashAlgorithm};
+use crate::index;
+use crate::ingest::{self, HashFile};
+use crate::proto::HashDatabase;
+use crate::utils;
+use crate::wal;
+
+//
+// Merge change files into the hash files of the default set while serving.
+//
+// The base of each changed table, which holds the hash file contents & any
+// compacted runtime changes, gets all change files applied in the order they
+// were made. Replaying changes that are already compacted into the base is
+// harmless, the last change of a hash wins either way.
+//
+// Each hash file is backed up, then replaced by writing a temporary file,
+// syncing & verifying it & renaming it over the hash file, the table is then
+// swapped in memory.
+// Change files are removed only once every table is swapped, so a failed
+// merge can simply be retried.
+//
+// With a data directory, the hash files are the base indexes in it & the
+// backups go to its backup directory, see `layout.rs`.
+//
+// Updates wait for a running merge, queries do not.
+//
+
+//
+// Replay change files into the in-memory tables, leaving the hash files
+// untouched, so acknowledged changes survive a restart without a merge.
+// Returns the number of replayed change files.
+//
+pub fn replay_change_files(database: &Database) -> Result<usize> {
+    let paths = wal::change_file_paths(&database.layout().change_file_dir())?;
+    if paths.is_empty() {
+        return Ok(0);
+    }
+
+    let now = Instant::now();
+
+    info!("Replaying {} change files, hash files are left untouched.", paths.len());
+    info!("Use \"--merge\" parameter or an online merge to merge into hash files.");
+
+    let changes = wal::parse_change_files(&paths, database.files())?;
+    for (algorithm, (added, removed)) in &changes {
+        let table = match database.get(*algorithm) {
+            Some(table) => table,
+            None => bail!("No {} hashes are loaded.", algorithm.name()),
+        };
+        table.replay(added, removed);
+        if table.needs_compaction() {
+            table.compact();
+        }
+    }
+
+    info!("Finished replaying change files.");
+    info!("Total time taken: {:.2?}.", now.elapsed());
+
+    Ok(paths.len())
+}
+
+//
+// Run a merge on a blocking thread.
+// Returns the number of merged change files.
+//
+pub async fn merge(hashes: HashDatabase) -> Result<usize> {
+    match tokio::task::spawn_blocking(move || merge_change_files(&hashes)).await {
+        Ok(result) => result,
+        Err(error) => bail!(error),
+    }
+}
+
+pub fn merge_change_files(database: &Database) -> Result<usize> {
+    let _change_log = database.change_log().blocking_lock();
+
+    let paths = wal::change_file_paths(&database.layout().change_file_dir())?;
+    if paths.is_empty() {
+        info!("No change files to merge.");
+        return Ok(0);
+    }
+
+    let now = Instant::now();
+
+    info!("Merging {} change files.", paths.len());
+
+    let changes = wal::parse_change_files(&paths, database.files())?;
+
+    // Check before touching any hash file
+    for hash_file in database.files() {
+        if changes.contains_key(&hash_file.algorithm) {
+            check_writable(hash_file)?;
+        }
+    }
+
+    for hash_file in database.files() {
+        let (added, removed) = match changes.get(&hash_file.algorithm) {
+            Some(changes) => changes,
+            None => continue,
+        };
+        let table = match database.get(hash_file.algorithm) {
+            Some(table) => table,
+            None => bail!("No {} hashes are loaded.", hash_file.algorithm.name()),
+        };
+
+        //
+        // Apply the changes to the base in a single pass
+        //
+        let snapshot = table.snapshot();
+        let (hashes, inserted, removed) = snapshot.base().apply(added, removed);
+        drop(snapshot);
+
+        info!("Applied changes to {} hashes, added: {}, removed: {}.",
+            hash_file.algorithm.name(), inserted, removed);
+
+        replace_hash_file(database, hash_file, hashes)?;
+    }
+
+    //
+    // Remove change files after all is done
+    //
+    remove_change_files(database, &paths)?;
+
+    info!("Finished merging hashes.");
+    info!("Total time taken: {:.2?}.", now.elapsed());
+
+    Ok(paths.len())
+}
+
+pub fn remove_change_files(database: &Database, paths: &[PathBuf]) -> Result<()> {
+    info!("Attempting to clean up change files.");
+    for path in paths {
+        match fs::remove_file(path) {
+            Ok(_) => info!("Removed change file \"{}\".", path.display()),
+            Err(error) => {
+                error!("Failed to remove change file \"{}\".", path.display());
+                // No point in bailing here
+                error!("{}", error);
+            }
+        }
+    }
+    utils::sync_directory(database.layout().change_file_dir())?;
+    Ok(())
+}
+
+//
+// Only text hash files & binary indexes can be rewritten.
+//
+pub fn check_writable(hash_file: &HashFile) -> Result<()> {
+    if !ingest::is_text(&hash_file.path)? && !ingest::is_binary(&hash_file.path)? {
+        bail!("Can only write text hash files or binary indexes, \"{}\" is neither.",
+            hash_file.path);
+    }
+    Ok(())
+}
+
+//
+// Backup a hash file of the default set, replace it by the given hashes &
+// swap them in memory, mapping the new index if indexes are mapped.
+//
+pub fn replace_hash_file(database: &Database, hash_file: &HashFile, hashes: HashTable) -> Result<()> {
+    let table = match database.get(hash_file.algorithm) {
+        Some(table) => table,
+        None => bail!("No {} hashes are loaded.", hash_file.algorithm.name()),
+    };
+
+    info!("Creating backup of the existing \"{}\" hash file.", hash_file.path);
+    backup_hash_file(hash_file, &database.layout().backup_dir(hash_file))?;
+
+    info!("Attempting to write changes to disk.");
+    let binary = ingest::is_binary(&hash_file.path)?;
+    write_hash_file(hash_file, &hashes, binary)?;
+
+    if binary && database.options().mmap {
+        table.replace(index::map_index(hash_file.algorithm, &hash_file.path)?);
+    } else {
+        table.replace(hashes);
+    }
+    info!("Swapped in new {} hashes.", hash_file.algorithm.name());
+
+    Ok(())
+}
+
+//
+// Copy the hash file to a timestamped backup in the backup directory,
+// keeping only the latest `globals::BACKUP_COUNT` backups.
+//
+fn backup_hash_file(hash_file: &HashFile, directory: &Path) -> Result<()> {
+    let path = Path::new(&hash_file.path);
+    let prefix = match path.file_name() {
+        Some(name) => name.to_string_lossy().into_owned() + ".",
+        None => bail!("Got invalid hash file path \"{}\".", hash_file.path),
+    };
+
+    let backup_file_name = directory.join(format!("{}{}.bak", prefix, utils::timestamp()?));
+    info!("Backing up hash file to \"{}\".", backup_file_name.display());
+    match fs::copy(&hash_file.path, &backup_file_name)
+        .and_then(|_| File::open(&backup_file_name)?.sync_all()) {
+        Ok(_) => {},
+        Err(error) => {
+            error!("Failed to backup hash file.");
+            bail!(error)
+        }
+    }
+
+    let mut backups: Vec<((u64, u32), PathBuf)> = vec![];
+    for entry in fs::read_dir(directory)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let time = match name.strip_prefix(&prefix).and_then(|name| name.strip_suffix(".bak")) {
+            Some(timestamp) => utils::file_time(timestamp),
+            None => continue,
+        };
+        if time != (0, 0) {
+            backups.push((time, entry.path()));
+        }
+    }
+    backups.sort();
+
+    let expired = backups.len().saturating_sub(globals::BACKUP_COUNT);
+    for (_, backup) in &backups[..expired] {
+        info!("Removing old backup \"{}\".", backup.display());
+        fs::remove_file(backup)?;
+    }
+
+    Ok(())
+}
+
+//
+// Replace a hash file through a new file beside it, which is synced &
+// verified before being renamed over the hash file. The hash file is
+// either the old or the new one, never a partial one.
+//
+pub fn write_hash_file(hash_file: &HashFile, table: &HashTable, binary: bool) -> Result<()> {
+    let temporary_path = hash_file.path.clone() + ".tmp";
+
+    if binary {
+        index::write_index(&temporary_path, table)?;
+    } else {
+        let mut writer = BufWriter::new(File::create(&temporary_path)?);
+        for hash in table.iter() {
+            match writer.write_fmt(format_args!("{}\n", hash::format_hash(hash))) {
+                Ok(_) => {},
+                Err(error) => {
+                    error!("Failed to write changes to hash file.");
+                    bail!("{}", error);
+                }
+            };
+        }
+        writer.into_inner()?.sync_all()?;
+    }
+
+    if let Err(error) = verify_hash_file(hash_file.algorithm, &temporary_path, table) {
+        error!("Failed to verify new hash file \"{}\".", temporary_path);
+        // The hash file is untouched, the new one is of no use
+        fs::remove_file(&temporary_path)?;
+        bail!(error);
+    }
+
+    fs::rename(&temporary_path, &hash_file.path)?;
+
+    // Persist the rename itself
+    utils::sync_directory(directory_of(Path::new(&hash_file.path)))?;
+
+    Ok(())
+}
+
+//
+// Read a written hash file back & check it holds the same number of hashes
+// as the table, in strictly ascending order.
+//
+fn verify_hash_file(algorithm: HashAlgorithm, path: &str, table: &HashTable) -> Result<()> {
+    let options = ingest::ReadOptions { require_sorted: true, mmap: false };
+    let written = ingest::read_hash_file(algorithm, path, &options)?;
+
+    if written.len() != table.len() {
+        bail!("New hash file holds {} hashes, expected {}.", written.len(), table.len());
+    }
+
+    let mut previous: Option<&[u8]> = None;
+    for (position, digest) in written.iter().enumerate() {
+        if previous.is_some_and(|previous| previous >= digest) {
+            bail!("New hash file is not sorted at hash {}.", position + 1);
+        }
+        previous = Some(digest);
+    }
+
+    info!("Verified new hash file of {} hashes.", written.len());
+
+    Ok(())
+}
+
+fn directory_of(path: &Path) -> &Path {
+    match path.parent() {
+        Some(directory) if !directory.as_os_str().is_empty() => directory,
+        _ => Path::new("."),
+    }
+}
@@ -0,0 +1,206 @@
+use anyhow::{Result, bail};
+
+use std::collections::HashMap;
+use std::path::Path;
+
+use log::info;
+
+use crate::hash::{self, HashAlgorithm, MAX_DIGEST_SIZE};
+use crate::sqlite::{self, Value};
+
+//
+// NSRL file metadata side table, loaded from the `FILE`, `PKG`, `OS` & `MFG`
+// tables of an RDSv3 release. Tells why a hash is known.
+//
+// Encoded record, see `Metadata::record`:
+// +-----------------+-----------------------------------------------+
+// |      Count      |                    Entries                    |
+// +-----------------+-----------------------------------------------+
+// | 2 bytes (u16be) | Count * (File size u64be, 6 * (u16be, UTF-8)) |
+// +-----------------+-----------------------------------------------+
+//
+// The strings of each entry are, in order: file name, product name,
+// product version, vendor, OS name & OS version.
+//
+
+struct Package {
+    name: String,
+    version: String,
+    vendor: String,
+    os_name: String,
+    os_version: String,
+}
+
+struct FileEntry {
+    name: String,
+    size: u64,
+    package: Option<usize>,
+}
+
+pub struct Metadata {
+    packages: Vec<Package>,
+    files: Vec<FileEntry>,
+    // Digest to file entries, per algorithm
+    digests: HashMap<HashAlgorithm, HashMap<Vec<u8>, Vec<usize>>>,
+}
+
+impl Metadata {
+    //
+    // Load metadata for the given algorithms from an RDSv3 SQLite database.
+    //
+    pub fn load<P>(path: P, algorithms: &[HashAlgorithm]) -> Result<Self>
+    where P: AsRef<Path>, {
+        let database = sqlite::Database::open(path)?;
+
+        //
+        // Vendors, operating systems & packages are small, read them first
+        //
+        let mut vendors: HashMap<i64, String> = HashMap::new();
+        let table = database.table("MFG")?;
+        let columns = find_columns(&table, "MFG", &["manufacturer_id", "name"])?;
+        database.scan(&table, |row| {
+            vendors.insert(integer(row[columns[0]]), text(row[columns[1]]));
+            Ok(())
+        })?;
+
+        let mut systems: HashMap<i64, (String, String)> = HashMap::new();
+        let table = database.table("OS")?;
+        let columns = find_columns(&table, "OS", &["operating_system_id", "name", "version"])?;
+        database.scan(&table, |row| {
+            systems.insert(integer(row[columns[0]]),
+                (text(row[columns[1]]), text(row[columns[2]])));
+            Ok(())
+        })?;
+
+        let mut packages: Vec<Package> = vec![];
+        let mut package_ids: HashMap<i64, usize> = HashMap::new();
+        let table = database.table("PKG")?;
+        let columns = find_columns(&table, "PKG",
+            &["package_id", "name", "version", "operating_system_id", "manufacturer_id"])?;
+        database.scan(&table, |row| {
+            let (os_name, os_version) = systems.get(&integer(row[columns[3]]))
+                .cloned()
+                .unwrap_or_default();
+            package_ids.insert(integer(row[columns[0]]), packages.len());
+            packages.push(Package {
+                name: text(row[columns[1]]),
+                version: text(row[columns[2]]),
+                vendor: vendors.get(&integer(row[columns[4]])).cloned().unwrap_or_default(),
+                os_name,
+                os_version,
+            });
+            Ok(())
+        })?;
+
+        info!("Loaded {} NSRL packages.", packages.len());
+
+        //
+        // One entry per file row, indexed by each requested digest
+        //
+        let table = database.table("FILE")?;
+        let columns = find_columns(&table, "FILE", &["file_name", "file_size", "package_id"])?;
+        let mut digest_columns: Vec<(HashAlgorithm, usize)> = vec![];
+        for algorithm in algorithms {
+            match table.column(algorithm.name()) {
+                Some(column) => digest_columns.push((*algorithm, column)),
+                None => bail!("Table \"FILE\" has no \"{}\" column.", algorithm.name()),
+            }
+        }
+
+        let mut files: Vec<FileEntry> = vec![];
+        let mut digests: HashMap<HashAlgorithm, HashMap<Vec<u8>, Vec<usize>>> = HashMap::new();
+        let mut buffer = [0u8; MAX_DIGEST_SIZE];
+
+        database.scan(&table, |row| {
+            for (algorithm, column) in &digest_columns {
+                let digest = &mut buffer[..algorithm.digest_size()];
+                match row[*column] {
+                    Value::Text(value) => {
+                        hash::parse_hash(*algorithm, String::from_utf8_lossy(value).trim(), digest)?;
+                    },
+                    Value::Blob(value) if value.len() == digest.len() => digest.copy_from_slice(value),
+                    _ => continue,
+                }
+                digests.entry(*algorithm)
+                    .or_default()
+                    .entry(digest.to_vec())
+                    .or_default()
+                    .push(files.len());
+            }
+            files.push(FileEntry {
+                name: text(row[columns[0]]),
+                size: integer(row[columns[1]]) as u64,
+                package: package_ids.get(&integer(row[columns[2]])).copied(),
+            });
+            Ok(())
+        })?;
+
+        info!("Loaded metadata of {} NSRL files.", files.len());
+
+        Ok(Metadata { packages, files, digests })
+    }
+
+    //
+    // Encoded metadata record of a digest, None if it has no metadata.
+    //
+    pub fn record(&self, algorithm: HashAlgorithm, digest: &[u8]) -> Option<Vec<u8>> {
+        let entries = self.digests.get(&algorithm)?.get(digest)?;
+        let count = entries.len().min(u16::MAX as usize);
+
+        let mut record = Vec::new();
+        record.extend_from_slice(&(count as u16).to_be_bytes());
+        for entry in &entries[..count] {
+            let file = &self.files[*entry];
+            let package = file.package.map(|package| &self.packages[package]);
+
+            record.extend_from_slice(&file.size.to_be_bytes());
+            push_string(&mut record, &file.name);
+            push_string(&mut record, package.map_or("", |package| &package.name));
+            push_string(&mut record, package.map_or("", |package| &package.version));
+            push_string(&mut record, package.map_or("", |package| &package.vendor));
+            push_string(&mut record, package.map_or("", |package| &package.os_name));
+            push_string(&mut record, package.map_or("", |package| &package.os_version));
+        }
+
+        Some(record)
+    }
+}
+
+fn find_columns(table: &sqlite::Table, name: &str, columns: &[&str]) -> Result<Vec<usize>> {
+    let mut positions = vec![];
+    for column in columns {
+        match table.column(column) {
+            Some(position) => positions.push(position),
+            None => bail!("Table \"{}\" has no \"{}\" column.", name, column),
+        }
+    }
+    Ok(positions)
+}
+
+fn integer(value: Value) -> i64 {
+    match value {
+        Value::Integer(value) => value,
+        Value::Text(value) => String::from_utf8_lossy(value).trim().parse().unwrap_or(0),
+        _ => 0,
+    }
+}
+
+fn text(value: Value) -> String {
+    match value {
+        Value::Text(value) => String::from_utf8_lossy(value).into_owned(),
+        Value::Integer(value) => value.to_string(),
+        _ => String::new(),
+    }
+}
+
+//
+// Strings are truncated to fit the u16be length, on a character boundary.
+//
+fn push_string(record: &mut Vec<u8>, string: &str) {
+    let mut end = string.len().min(u16::MAX as usize);
+    while !string.is_char_boundary(end) {
+        end -= 1;
+    }
+    record.extend_from_slice(&(end as u16).to_be_bytes());
+    record.extend_from_slice(&string.as_bytes()[..end]);
+}
@@ -0,0 +1,61 @@
+use anyhow::{Result, bail};
+
+use std::fs::File;
+use std::io;
+use std::os::fd::AsRawFd;
+
+//
+// Read-only, shared memory map of a whole file.
+// Pages are backed by the page cache, so several processes mapping the same
+// file share the memory.
+//
+pub struct Mmap {
+    pointer: *mut libc::c_void,
+    length: usize,
+}
+
+// The mapping is read-only & never changes, sharing between threads is safe
+unsafe impl Send for Mmap {}
+unsafe impl Sync for Mmap {}
+
+impl Mmap {
+    pub fn map(file: &File) -> Result<Self> {
+        let length = file.metadata()?.len() as usize;
+        if length == 0 {
+            bail!("Can not map an empty file.");
+        }
+
+        let pointer = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                length,
+                libc::PROT_READ,
+                libc::MAP_SHARED,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if pointer == libc::MAP_FAILED {
+            bail!(io::Error::last_os_error());
+        }
+
+        // Binary searches jump around, read-ahead would only waste memory
+        unsafe {
+            libc::madvise(pointer, length, libc::MADV_RANDOM);
+        }
+
+        Ok(Mmap { pointer, length })
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.pointer as *const u8, self.length) }
+    }
+}
+
+impl Drop for Mmap {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.pointer, self.length);
+        }
+    }
+}
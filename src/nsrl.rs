@@ -0,0 +1,152 @@
+use anyhow::Result;
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Instant;
+
+use log::{info, error};
+
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+
+use crate::database::Snapshot;
+use crate::hash::{self, HashAlgorithm, MAX_DIGEST_SIZE};
+use crate::proto::HashDatabase;
+
+//
+// Line protocol of nsrlsvr, so nsrllookup based clients (e.g. Autopsy) can
+// query the default set unchanged. Served on its own port (`--nsrl-port`).
+//
+// Every line ends with "\r\n" (a bare "\n" is accepted too) & is answered
+// by a single line, except for "BYE":
+//
+//   Version: 2.0             OK, required before queries, 1.0 is accepted
+//   query <hash> <hash> ...  OK <one "1" or "0" per hash, in order>
+//   BYE                      closes the connection
+//
+// Hashes are hex MD5, SHA-1 or SHA-256 of any served algorithm. Anything
+// else, including a query before the version line, is answered by "NOT OK"
+// & ends the connection.
+//
+
+// Longest accepted line, about 130000 MD5 hashes
+const MAX_LINE_SIZE: u64 = 1 << 22;
+
+const REPLY_OK: &str = "OK";
+const REPLY_NOT_OK: &str = "NOT OK";
+
+pub async fn handle_client(socket: &mut TcpStream, hashes: &HashDatabase) {
+    match handle_connection(socket, hashes).await {
+        Ok(_) => {},
+        Err(error) => {
+            error!("Failed to serve NSRL client.");
+            error!("{}", error);
+        }
+    };
+    match socket.shutdown().await {
+        Ok(_) => {},
+        Err(error) => {
+            error!("Failed to close connection.");
+            error!("{}", error);
+        }
+    };
+}
+
+async fn handle_connection(socket: &mut TcpStream, hashes: &HashDatabase) -> Result<()> {
+    let (reader, mut writer) = socket.split();
+    let mut reader = BufReader::new(reader);
+    let mut line = vec![];
+    let mut greeted = false;
+
+    loop {
+        line.clear();
+        if (&mut reader).take(MAX_LINE_SIZE).read_until(b'\n', &mut line).await? == 0 {
+            break;
+        }
+        // Cut short by the size limit or the end of the connection
+        if !line.ends_with(b"\n") {
+            error!("Received an incomplete NSRL line.");
+            writer.write_all(reply(REPLY_NOT_OK).as_bytes()).await?;
+            break;
+        }
+
+        let text = String::from_utf8_lossy(&line);
+        let text = text.trim();
+        let (command, arguments) = text.split_once(char::is_whitespace).unwrap_or((text, ""));
+
+        let response = if command.eq_ignore_ascii_case("version:") {
+            match arguments.trim() {
+                "1.0" | "2.0" => {
+                    greeted = true;
+                    REPLY_OK.to_owned()
+                },
+                version => {
+                    error!("Received unsupported NSRL protocol version \"{}\".", version);
+                    REPLY_NOT_OK.to_owned()
+                },
+            }
+        } else if command.eq_ignore_ascii_case("query") && !greeted {
+            error!("Received an NSRL query before the version line.");
+            REPLY_NOT_OK.to_owned()
+        } else if command.eq_ignore_ascii_case("query") {
+            match query(hashes, arguments) {
+                Some(results) => format!("{} {}", REPLY_OK, results),
+                None => REPLY_NOT_OK.to_owned(),
+            }
+        } else if command.eq_ignore_ascii_case("bye") {
+            break;
+        } else {
+            error!("Received invalid NSRL command \"{}\".", command);
+            REPLY_NOT_OK.to_owned()
+        };
+
+        writer.write_all(reply(&response).as_bytes()).await?;
+        if response == REPLY_NOT_OK {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+fn reply(response: &str) -> String {
+    response.to_owned() + "\r\n"
+}
+
+//
+// Search the default set, None if any hash is invalid or of an algorithm
+// that is not served.
+//
+fn query(hashes: &HashDatabase, arguments: &str) -> Option<String> {
+    let now = Instant::now();
+
+    let mut snapshots: HashMap<HashAlgorithm, Arc<Snapshot>> = HashMap::new();
+    let mut buffer = [0u8; MAX_DIGEST_SIZE];
+    let mut results = String::new();
+
+    for argument in arguments.split_whitespace() {
+        let algorithm = HashAlgorithm::from_hex_size(argument.len())
+            .filter(|algorithm| hashes.get(*algorithm).is_some());
+        let algorithm = match algorithm {
+            Some(algorithm) => algorithm,
+            None => {
+                error!("Received NSRL hash \"{}\" of no served algorithm.", argument);
+                return None;
+            },
+        };
+        let table = snapshots.entry(algorithm)
+            .or_insert_with(|| hashes.get(algorithm).unwrap().snapshot());
+
+        let digest = &mut buffer[..algorithm.digest_size()];
+        if hash::parse_hash(algorithm, argument, digest).is_err() {
+            error!("Received invalid NSRL hash \"{}\".", argument);
+            return None;
+        }
+        results.push(if table.contains(digest) { '1' } else { '0' });
+    }
+
+    info!("Received an NSRL query with {} hashes.", results.len());
+    info!("Total time taken: {:.2?}.", now.elapsed());
+
+    Some(results)
+}
@@ -1,21 +1,29 @@
-use anyhow::{Result, bail};
+use anyhow::{Result, anyhow, bail};
 
+use std::fmt;
 use std::fs;
-use std::io::Write;
+use std::io::ErrorKind;
 use std::time::Instant;
 use std::sync::Arc;
 use std::fs::File;
+use std::path::Path;
 
 use log::{info, error};
 
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpStream;
-use tokio::sync::Mutex;
+use tokio::net::tcp::ReadHalf;
+use tokio::sync::{Semaphore, mpsc};
 
-use crate::globals;
+use crate::database::Database;
+use crate::hash::HashAlgorithm;
+use crate::merge;
+use crate::replication::{self, Replication};
+use crate::snapshot;
+use crate::wal;
 use crate::utils;
 
-pub type HashDatabase = Arc<Mutex<Vec<(u64, u64)>>>;
+pub type HashDatabase = Arc<Database>;
 
 //
 // Here is the application layer protocol
@@ -25,11 +33,22 @@ pub type HashDatabase = Arc<Mutex<Vec<(u64, u64)>>>;
 // +---------+---------+-----------------+-------------------------------+
 // | Version | Command |     Length      |      Argumetns / Hashes       |
 // +---------+---------+-----------------+-------------------------------+
-// | 1 byte  | 1 byte  | 2 bytes (u16be) | Length * (u64be, u64be) bytes |
+// | 1 byte  | 1 byte  | 2 bytes (u16be) | Length * digest size bytes    |
 // +---------+---------+-----------------+-------------------------------+
 //
-// See `From<u8>` implementations for both ProtoVersion & ProtoCommand
-// for available versions & commands.
+// Hashes are sent as raw big-endian digests, 16 bytes for MD5, 20 bytes for
+// SHA-1 & 32 bytes for SHA-256. Each algorithm is served from its own index.
+// A connection starts out using MD5, the algorithm command switches it
+// (and the index used) for all following commands:
+//
+// +---------+---------+-----------+
+// | Version | Command | Algorithm |
+// +---------+---------+-----------+
+// | 1 byte  | 1 byte  | 1 byte    |
+// +---------+---------+-----------+
+//
+// See `From<u8>` implementations for ProtoVersion, ProtoCommand &
+// ProtoAlgorithm for available versions, commands & algorithms.
 // 
 // Responses:
 // +--------+---------+
@@ -38,18 +57,205 @@ pub type HashDatabase = Arc<Mutex<Vec<(u64, u64)>>>;
 // | 1 byte | * bytes |
 // +--------+---------+
 //
+// The data of an error status is an error code & message:
+//
+// +-----------------+-----------------+--------------+
+// |      Code       |     Length      |   Message    |
+// +-----------------+-----------------+--------------+
+// | 2 bytes (u16be) | 2 bytes (u16be) | Length bytes |
+// +-----------------+-----------------+--------------+
+//
+// See `From<ProtoError>` for the codes. The connection stays open after an
+// error, unless the request could not be read in full, such as an invalid
+// version or a truncated request, or the code is 0 for an internal error.
+// Those errors end the connection. Unknown commands are taken to have no
+// arguments.
+//
+// The data of a query is one found byte per hash, 1 for hits & 0 for misses.
+// The encoding command switches the encoding of query responses for all
+// following queries, it takes one byte like the algorithm command:
+//
+//   0  found bytes, the default
+//   1  bitset, bit N % 8 (least significant first) of byte N / 8 is set for
+//      hits, padded to whole bytes
+//   2  list of the indices of misses
+//   3  list of the indices of hits
+//   4  the smallest of 1, 2 & 3, misses winning ties
+//
+// Encodings other than 0 lead with a byte of the encoding used, so clients
+// know which one 4 picked. Lists are a u32be count followed by the indices,
+// u16be for queries of up to `INDEX_U16_LIMIT` hashes, u32be otherwise:
+//
+// +----------+-----------------+------------------------------+
+// | Encoding |      Count      |           Indices            |
+// +----------+-----------------+------------------------------+
+// | 1 byte   | 4 bytes (u32be) | Count * 2 or 4 bytes         |
+// +----------+-----------------+------------------------------+
+//
+// Query streams are always answered by found bytes.
+//
+// The metadata query takes the same arguments as a query, the data holds
+// for each hash a found byte, followed for hits by a metadata record:
+//
+// +--------+-----------------+-------------------------+
+// | Found  |     Length      |         Record          |
+// +--------+-----------------+-------------------------+
+// | 1 byte | 4 bytes (u32be) | Length bytes, hits only |
+// +--------+-----------------+-------------------------+
+//
+// See `metadata.rs` for the record layout. Hits without metadata, such as
+// runtime updates, have a zero length record.
+//
+// The classify query takes the same arguments as a query & checks every
+// named hash set, the data holds for each hash:
+//
+// +----------+-----------------+
+// | Category |    Set mask     |
+// +----------+-----------------+
+// | 1 byte   | 4 bytes (u32be) |
+// +----------+-----------------+
+//
+// Bit N of the mask is set if the hash is in set N. The category is the
+// combination of the matched set categories, 0 for unknown, 1 for known-good,
+// 2 for known-bad & 3 for both. Sets are listed by the list sets command,
+// which takes no arguments:
+//
+// +--------+-----------------------------------------------------+
+// | Count  |                        Sets                         |
+// +--------+-----------------------------------------------------+
+// | 1 byte | Count * (Category 1 byte, Length 1 byte, Name bytes) |
+// +--------+-----------------------------------------------------+
+//
+// Set 0 is the default set given by `--hash-file`, the only one that
+// receives updates & deletions. Both take the same arguments as a query,
+// the data is the number of hashes actually added or removed:
+//
+// +-----------------+
+// |      Count      |
+// +-----------------+
+// | 2 bytes (u16be) |
+// +-----------------+
+//
+// The merge command takes no arguments & merges the change files into the
+// hash files while serving, the data is the number of merged change files:
+//
+// +-----------------+
+// |      Count      |
+// +-----------------+
+// | 4 bytes (u32be) |
+// +-----------------+
+//
+// The snapshot & restore commands take the name of a snapshot, see
+// `snapshot.rs`, the data is the number of hashes in the snapshot:
+//
+// +---------+---------+--------+--------------+
+// | Version | Command | Length |     Name     |
+// +---------+---------+--------+--------------+
+// | 1 byte  | 1 byte  | 1 byte | Length bytes |
+// +---------+---------+--------+--------------+
+//
+// +-----------------+
+// |      Count      |
+// +-----------------+
+// | 4 bytes (u32be) |
+// +-----------------+
+//
+// The follow command takes no arguments & turns the connection into a
+// replication stream of the default set, see `replication.rs`.
+//
+// Followers only serve queries, updates, deletions & admin commands are
+// rejected.
+//
+// Version "s" streams queries of any size, without waiting for a response
+// per batch. Only the query command is streamed, using the algorithm of the
+// connection, followed by any number of batches & an empty batch to end the
+// stream:
+//
+// +---------+---------+
+// | Version | Command |
+// +---------+---------+
+// | 1 byte  | 1 byte  |
+// +---------+---------+
+//
+// +-----------------+----------------------------+
+// |      Count      |           Hashes           |
+// +-----------------+----------------------------+
+// | 2 bytes (u16be) | Count * digest size bytes  |
+// +-----------------+----------------------------+
+//
+// Each batch is answered in order as soon as it is searched, by a frame of
+// one found byte per hash, the empty batch by an empty frame:
+//
+// +--------+-----------------+--------------+
+// | Status |      Count      |   Results    |
+// +--------+-----------------+--------------+
+// | 1 byte | 2 bytes (u16be) | Count bytes  |
+// +--------+-----------------+--------------+
+//
+// Batches keep being read while results are written, up to
+// `STREAM_BACKLOG` batches ahead, so clients should read results while
+// sending. The connection then takes commands of any version again.
+//
+// Version 2 frames every request, so a client can send many requests without
+// waiting & match the responses by request ID. Once a connection sent a
+// version 2 frame, it only takes version 2 frames:
+//
+// +---------+------------+---------+--------+-----------------+---------+
+// | Version | Request ID | Command | Flags  |     Length      | Payload |
+// +---------+------------+---------+--------+-----------------+---------+
+// | 1 byte  | 4 bytes    | 1 byte  | 1 byte | 4 bytes (u32be) | Length  |
+// |         | (u32be)    |         |        |                 | bytes   |
+// +---------+------------+---------+--------+-----------------+---------+
+//
+// Request IDs are chosen by the client & only echoed by the server. The
+// flags of a query are its response encoding, see above, other commands take
+// no flags. Payloads are at most `MAX_PAYLOAD_SIZE` bytes.
+// Hash commands (query, metadata query, classify query, update & delete)
+// carry the algorithm, there is no connection algorithm:
+//
+// +-----------+-----------------------------+
+// | Algorithm |           Hashes            |
+// +-----------+-----------------------------+
+// | 1 byte    | Length - 1 bytes of digests |
+// +-----------+-----------------------------+
+//
+// The snapshot & restore payload is the name, list sets & merge take none.
+// The end command closes the connection once all running requests are
+// answered, the follow, algorithm & encoding commands are not available.
+//
+// Up to `PIPELINE_DEPTH` requests run at once, each response is sent as soon
+// as its request is done, so responses may come in any order:
+//
+// +------------+--------+-----------------+--------------+
+// | Request ID | Status |     Length      |     Data     |
+// +------------+--------+-----------------+--------------+
+// | 4 bytes    | 1 byte | 4 bytes (u32be) | Length bytes |
+// | (u32be)    |        |                 |              |
+// +------------+--------+-----------------+--------------+
+//
+// The data is the same as in version 1, except that update & delete counts
+// are u32be. Failed requests are answered by their error code & message,
+// see above, & the connection stays open. A broken frame closes the
+// connection without a response.
+//
+
+
+// Batches of a query stream read ahead of the results
+const STREAM_BACKLOG: usize = 64;
+
+// Requests of a version 2 connection run at once
+const PIPELINE_DEPTH: usize = 64;
 
-const ERROR_INVALID_LENGTH: &str = "ERROR_INVALID_LENGTH";
-const ERROR_INVALID_PROTO_VERSION: &str = "ERROR_INVALID_PROTO_VERSION";
-const ERROR_INVALID_COMMAND: &str = "ERROR_INVALID_COMMAND";
-const ERROR_READ_FAIL: &str = "ERROR_READ_FAIL";
-const ERROR_CHANGE_DIR_CHECK_FAIL: &str = "ERROR_CHANGE_DIR_CHECK_FAIL";
-const ERROR_CHANGE_FILE_CREATE_FAIL: &str = "ERROR_CHANGE_FILE_CREATE_FAIL";
-const ERROR_CHANGE_FILE_WRITE_FAIL: &str = "ERROR_CHANGE_FILE_WRITE_FAIL";
-const ERROR_CHANGE_FILE_REMOVE_FAIL: &str = "ERROR_CHANGE_FILE_REMOVE_FAIL";
+// Largest version 2 payload, about 4 million MD5 hashes
+const MAX_PAYLOAD_SIZE: u32 = 1 << 26;
+
+// Queries of up to this many hashes encode indices as u16
+const INDEX_U16_LIMIT: usize = 1 << 16;
 
 enum ProtoVersion {
     V1,
+    V2,
+    Stream,
     Unknown,
 }
 
@@ -57,6 +263,8 @@ impl From<u8> for ProtoVersion {
     fn from(byte: u8) -> Self {
         match byte {
             b'1' => ProtoVersion::V1,
+            b'2' => ProtoVersion::V2,
+            b's' => ProtoVersion::Stream,
             _ => ProtoVersion::Unknown,
         }
     }
@@ -64,7 +272,17 @@ impl From<u8> for ProtoVersion {
 
 enum ProtoCommand {
     Query,
+    MetadataQuery,
+    ClassifyQuery,
+    ListSets,
     Update,
+    Delete,
+    Merge,
+    Snapshot,
+    Restore,
+    Follow,
+    Algorithm,
+    Encoding,
     End,
     Unknown,
 }
@@ -73,39 +291,186 @@ impl From<u8> for ProtoCommand {
     fn from(byte: u8) -> Self {
         match byte {
             b'q' => ProtoCommand::Query,
+            b'm' => ProtoCommand::MetadataQuery,
+            b'c' => ProtoCommand::ClassifyQuery,
+            b'l' => ProtoCommand::ListSets,
             b'u' => ProtoCommand::Update,
+            b'd' => ProtoCommand::Delete,
+            b'k' => ProtoCommand::Merge,
+            b'n' => ProtoCommand::Snapshot,
+            b'r' => ProtoCommand::Restore,
+            b'f' => ProtoCommand::Follow,
+            b'a' => ProtoCommand::Algorithm,
+            b'o' => ProtoCommand::Encoding,
             b'e' => ProtoCommand::End,
             _ => ProtoCommand::Unknown,
         }
     }
 }
 
+enum ProtoAlgorithm {
+    Md5,
+    Sha1,
+    Sha256,
+    Unknown,
+}
+
+impl From<u8> for ProtoAlgorithm {
+    fn from(byte: u8) -> Self {
+        match byte {
+            b'5' => ProtoAlgorithm::Md5,
+            b'1' => ProtoAlgorithm::Sha1,
+            b'2' => ProtoAlgorithm::Sha256,
+            _ => ProtoAlgorithm::Unknown,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum ProtoEncoding {
+    Bytes,
+    Bitset,
+    Misses,
+    Hits,
+    Smallest,
+    Unknown,
+}
+
+impl From<u8> for ProtoEncoding {
+    fn from(byte: u8) -> Self {
+        match byte {
+            0 => ProtoEncoding::Bytes,
+            1 => ProtoEncoding::Bitset,
+            2 => ProtoEncoding::Misses,
+            3 => ProtoEncoding::Hits,
+            4 => ProtoEncoding::Smallest,
+            _ => ProtoEncoding::Unknown,
+        }
+    }
+}
+
+impl From<ProtoEncoding> for u8 {
+    fn from(encoding: ProtoEncoding) -> u8 {
+        match encoding {
+            ProtoEncoding::Bytes => 0,
+            ProtoEncoding::Bitset => 1,
+            ProtoEncoding::Misses => 2,
+            ProtoEncoding::Hits => 3,
+            ProtoEncoding::Smallest => 4,
+            ProtoEncoding::Unknown => u8::MAX,
+        }
+    }
+}
+
 enum ProtoResponseStatus {
     Success,
     Error,
 }
 
-impl Into<u8> for ProtoResponseStatus {
-    fn into(self) -> u8 {
-        match self {
-            Self::Success => b's',
-            Self::Error => b'e',
+impl From<ProtoResponseStatus> for u8 {
+    fn from(status: ProtoResponseStatus) -> u8 {
+        match status {
+            ProtoResponseStatus::Success => b's',
+            ProtoResponseStatus::Error => b'e',
+        }
+    }
+}
+
+//
+// Error codes of error responses, see the protocol description above.
+//
+#[derive(Clone, Copy, Debug)]
+enum ProtoError {
+    Internal,
+    InvalidProtoVersion,
+    InvalidCommand,
+    InvalidLength,
+    ReadFail,
+    InvalidAlgorithm,
+    UnsupportedAlgorithm,
+    InvalidFlags,
+    PayloadTooLarge,
+    InvalidName,
+    ReadOnly,
+    ChangeDirCheckFail,
+    ChangeFileCreateFail,
+    ChangeFileWriteFail,
+    ChangeFileRemoveFail,
+    MergeFail,
+    SnapshotFail,
+    RestoreFail,
+    InvalidEncoding,
+}
+
+impl From<ProtoError> for u16 {
+    fn from(error: ProtoError) -> u16 {
+        match error {
+            ProtoError::Internal => 0,
+            ProtoError::InvalidProtoVersion => 1,
+            ProtoError::InvalidCommand => 2,
+            ProtoError::InvalidLength => 3,
+            ProtoError::ReadFail => 4,
+            ProtoError::InvalidAlgorithm => 5,
+            ProtoError::UnsupportedAlgorithm => 6,
+            ProtoError::InvalidFlags => 7,
+            ProtoError::PayloadTooLarge => 8,
+            ProtoError::InvalidName => 9,
+            ProtoError::ReadOnly => 10,
+            ProtoError::ChangeDirCheckFail => 11,
+            ProtoError::ChangeFileCreateFail => 12,
+            ProtoError::ChangeFileWriteFail => 13,
+            ProtoError::ChangeFileRemoveFail => 14,
+            ProtoError::MergeFail => 15,
+            ProtoError::SnapshotFail => 16,
+            ProtoError::RestoreFail => 17,
+            ProtoError::InvalidEncoding => 18,
         }
     }
 }
 
+impl fmt::Display for ProtoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message = match self {
+            ProtoError::Internal => "Internal server error.",
+            ProtoError::InvalidProtoVersion => "Invalid protocol version.",
+            ProtoError::InvalidCommand => "Invalid command.",
+            ProtoError::InvalidLength => "Invalid length.",
+            ProtoError::ReadFail => "Failed to read request.",
+            ProtoError::InvalidAlgorithm => "Invalid hash algorithm.",
+            ProtoError::UnsupportedAlgorithm => "Hash algorithm is not served.",
+            ProtoError::InvalidFlags => "Invalid request flags.",
+            ProtoError::PayloadTooLarge => "Payload too large.",
+            ProtoError::InvalidName => "Invalid snapshot name.",
+            ProtoError::ReadOnly => "Server is a read-only follower.",
+            ProtoError::ChangeDirCheckFail => "Failed to check change file directory.",
+            ProtoError::ChangeFileCreateFail => "Failed to create change file.",
+            ProtoError::ChangeFileWriteFail => "Failed to write change file.",
+            ProtoError::ChangeFileRemoveFail => "Failed to remove change file.",
+            ProtoError::MergeFail => "Failed to merge change files.",
+            ProtoError::SnapshotFail => "Failed to take snapshot.",
+            ProtoError::RestoreFail => "Failed to restore snapshot.",
+            ProtoError::InvalidEncoding => "Invalid response encoding.",
+        };
+        write!(f, "{}", message)
+    }
+}
+
+impl std::error::Error for ProtoError {}
+
+impl ProtoError {
+    //
+    // Errors after which the request stream can not be followed anymore.
+    //
+    fn is_fatal(&self) -> bool {
+        matches!(self, ProtoError::Internal | ProtoError::InvalidProtoVersion | ProtoError::ReadFail)
+    }
+}
+
 pub async fn handle_client(socket: &mut TcpStream, hashes: &HashDatabase) {
-    match handle_connection(socket, &hashes).await {
+    match handle_connection(socket, hashes).await {
         Ok(_) => {},
         Err(error) => {
-            match socket.write_u8(ProtoResponseStatus::Error.into()).await {
-                Ok(_) => {},
-                Err(error) => {
-                    error!("Failed to write error to client.");
-                    error!("{}", error);
-                }
-            };
-            match socket.write_all(error.to_string().as_bytes()).await {
+            match socket.write_all(&error_response(&error)).await {
                 Ok(_) => {},
                 Err(error) => {
                     error!("Failed to write error to client.");
@@ -125,150 +490,1014 @@ pub async fn handle_client(socket: &mut TcpStream, hashes: &HashDatabase) {
 }
 
 pub async fn handle_connection(socket: &mut TcpStream, hashes: &HashDatabase) -> Result<()> {
+    let mut algorithm = HashAlgorithm::Md5;
+    let mut encoding = ProtoEncoding::Bytes;
     loop {
         //
         // Read the first byte as the protocol version 
         // 
-        match ProtoVersion::from(socket.read_u8().await.unwrap_or_else(|_| 0)) {
+        let result = match ProtoVersion::from(socket.read_u8().await.unwrap_or(0)) {
             //
             // Handle version 1
-            // Likely the only version there will ever be but still
             //
             ProtoVersion::V1 => {
                 //
                 // Read next byte as the command
                 // Handle all cases
                 //
-                match ProtoCommand::from(socket.read_u8().await.unwrap_or_else(|_| 0)) {
-                    ProtoCommand::Query => handle_v1_query(socket, &hashes).await?,
-                    ProtoCommand::Update => handle_v1_update(socket, &hashes).await?,
+                match ProtoCommand::from(socket.read_u8().await.unwrap_or(0)) {
+                    ProtoCommand::Follow if hashes.is_read_only() => {
+                        error!("Received a follow command on a follower.");
+                        Err(anyhow!(ProtoError::ReadOnly))
+                    },
+                    ProtoCommand::Query => {
+                        handle_v1_query(socket, hashes, algorithm, encoding).await
+                    },
+                    ProtoCommand::MetadataQuery => {
+                        handle_v1_metadata_query(socket, hashes, algorithm).await
+                    },
+                    ProtoCommand::ClassifyQuery => {
+                        handle_v1_classify_query(socket, hashes, algorithm).await
+                    },
+                    ProtoCommand::ListSets => handle_v1_list_sets(socket, hashes).await,
+                    ProtoCommand::Update => handle_v1_update(socket, hashes, algorithm).await,
+                    ProtoCommand::Delete => handle_v1_delete(socket, hashes, algorithm).await,
+                    ProtoCommand::Merge => handle_v1_merge(socket, hashes).await,
+                    ProtoCommand::Snapshot => handle_v1_snapshot(socket, hashes).await,
+                    ProtoCommand::Restore => handle_v1_restore(socket, hashes).await,
+                    ProtoCommand::Follow => {
+                        handle_v1_follow(socket, hashes).await?;
+                        break;
+                    },
+                    ProtoCommand::Algorithm => {
+                        handle_v1_algorithm(socket, hashes).await
+                            .map(|switched| algorithm = switched)
+                    },
+                    ProtoCommand::Encoding => {
+                        handle_v1_encoding(socket).await
+                            .map(|switched| encoding = switched)
+                    },
                     ProtoCommand::End => break,
+                    // Taken to have no arguments, so the connection goes on
                     ProtoCommand::Unknown => {
                         error!("Received invalid protocol command.");
-                        bail!(ERROR_INVALID_COMMAND);
+                        Err(anyhow!(ProtoError::InvalidCommand))
                     },
                 }
             }
+            ProtoVersion::Stream => {
+                match ProtoCommand::from(socket.read_u8().await.unwrap_or(0)) {
+                    ProtoCommand::Query => handle_stream_query(socket, hashes, algorithm).await,
+                    _ => {
+                        error!("Received invalid stream command.");
+                        Err(anyhow!(ProtoError::InvalidCommand))
+                    },
+                }
+            },
+            //
+            // Version 2 frames carry their own request ID, the connection
+            // stays on version 2 until it ends
+            //
+            ProtoVersion::V2 => {
+                match handle_v2(socket, hashes).await {
+                    Ok(_) => {},
+                    Err(error) => {
+                        error!("Closing version 2 connection.");
+                        error!("{}", error);
+                    }
+                };
+                break;
+            },
             ProtoVersion::Unknown => {
                 error!("Received invalid protocol version.");
-                bail!(ERROR_INVALID_PROTO_VERSION);
+                bail!(ProtoError::InvalidProtoVersion);
             },
         };
+
+        //
+        // Answer errors that leave the request stream intact & go on,
+        // others end the connection
+        //
+        match result {
+            Ok(_) => {},
+            Err(error) if !error_code(&error).is_fatal() => {
+                socket.write_all(&error_response(&error)).await?;
+            },
+            Err(error) => return Err(error),
+        }
     }
     Ok(())
 }
 
-async fn handle_v1_query(socket: &mut TcpStream, hashes: &HashDatabase) -> Result<()> {
-    let hash_count = match socket.read_u16().await {
-        Ok(n) => n,
-        Err(_) => {
-            error!("Failed to receive length.");
-            bail!(ERROR_INVALID_LENGTH);
+//
+// Switch the algorithm used by the following commands on this connection.
+// Fails if the server does not serve the requested algorithm.
+//
+async fn handle_v1_algorithm(socket: &mut TcpStream, hashes: &HashDatabase) -> Result<HashAlgorithm> {
+    let algorithm = parse_algorithm(hashes, socket.read_u8().await.unwrap_or(0))?;
+
+    info!("Switched connection to {} hashes.", algorithm.name());
+
+    socket.write_u8(ProtoResponseStatus::Success.into()).await?;
+
+    Ok(algorithm)
+}
+
+//
+// Switch the encoding of query responses on this connection.
+//
+async fn handle_v1_encoding(socket: &mut TcpStream) -> Result<ProtoEncoding> {
+    let encoding = parse_encoding(socket.read_u8().await.unwrap_or(u8::MAX))?;
+
+    info!("Switched connection to query response encoding {}.", u8::from(encoding));
+
+    socket.write_u8(ProtoResponseStatus::Success.into()).await?;
+
+    Ok(encoding)
+}
+
+async fn handle_v1_query(
+    socket: &mut TcpStream,
+    hashes: &HashDatabase,
+    algorithm: HashAlgorithm,
+    encoding: ProtoEncoding,
+) -> Result<()> {
+    let digests = read_v1_digests(socket, algorithm).await?;
+    let results = encode_results(query(hashes, algorithm, &digests)?, encoding);
+
+    socket.write_u8(ProtoResponseStatus::Success.into()).await?;
+    socket.write_all(&results).await?;
+
+    Ok(())
+}
+
+async fn handle_v1_metadata_query(
+    socket: &mut TcpStream,
+    hashes: &HashDatabase,
+    algorithm: HashAlgorithm,
+) -> Result<()> {
+    let digests = read_v1_digests(socket, algorithm).await?;
+    let results = metadata_query(hashes, algorithm, &digests)?;
+
+    socket.write_u8(ProtoResponseStatus::Success.into()).await?;
+    socket.write_all(&results).await?;
+
+    Ok(())
+}
+
+async fn handle_v1_classify_query(
+    socket: &mut TcpStream,
+    hashes: &HashDatabase,
+    algorithm: HashAlgorithm,
+) -> Result<()> {
+    let digests = read_v1_digests(socket, algorithm).await?;
+    let results = classify_query(hashes, algorithm, &digests);
+
+    socket.write_u8(ProtoResponseStatus::Success.into()).await?;
+    socket.write_all(&results).await?;
+
+    Ok(())
+}
+
+async fn handle_v1_list_sets(socket: &mut TcpStream, hashes: &HashDatabase) -> Result<()> {
+    let results = list_sets(hashes);
+
+    socket.write_u8(ProtoResponseStatus::Success.into()).await?;
+    socket.write_all(&results).await?;
+
+    Ok(())
+}
+
+async fn handle_v1_update(
+    socket: &mut TcpStream,
+    hashes: &HashDatabase,
+    algorithm: HashAlgorithm,
+) -> Result<()> {
+    let digests = read_v1_digests(socket, algorithm).await?;
+    let inserted = update(hashes, algorithm, &digests).await?;
+
+    socket.write_u8(ProtoResponseStatus::Success.into()).await?;
+    socket.write_u16(inserted as u16).await?;
+
+    Ok(())
+}
+
+async fn handle_v1_delete(
+    socket: &mut TcpStream,
+    hashes: &HashDatabase,
+    algorithm: HashAlgorithm,
+) -> Result<()> {
+    let digests = read_v1_digests(socket, algorithm).await?;
+    let removed = delete(hashes, algorithm, &digests).await?;
+
+    socket.write_u8(ProtoResponseStatus::Success.into()).await?;
+    socket.write_u16(removed as u16).await?;
+
+    Ok(())
+}
+
+async fn handle_v1_merge(socket: &mut TcpStream, hashes: &HashDatabase) -> Result<()> {
+    let merged = merge(hashes).await?;
+
+    socket.write_u8(ProtoResponseStatus::Success.into()).await?;
+    socket.write_u32(merged as u32).await?;
+
+    Ok(())
+}
+
+async fn handle_v1_snapshot(socket: &mut TcpStream, hashes: &HashDatabase) -> Result<()> {
+    let name = read_name(socket).await?;
+    let count = take_snapshot(hashes, name).await?;
+
+    socket.write_u8(ProtoResponseStatus::Success.into()).await?;
+    socket.write_u32(count as u32).await?;
+
+    Ok(())
+}
+
+async fn handle_v1_restore(socket: &mut TcpStream, hashes: &HashDatabase) -> Result<()> {
+    let name = read_name(socket).await?;
+    let count = restore_snapshot(hashes, name).await?;
+
+    socket.write_u8(ProtoResponseStatus::Success.into()).await?;
+    socket.write_u32(count as u32).await?;
+
+    Ok(())
+}
+
+//
+// Replicate the default set to a follower for as long as it is connected.
+//
+async fn handle_v1_follow(socket: &mut TcpStream, hashes: &HashDatabase) -> Result<()> {
+    info!("Received a follow request.");
+
+    socket.write_u8(ProtoResponseStatus::Success.into()).await?;
+    replication::serve_follower(socket, hashes).await
+}
+
+//
+// Search a stream of batches, reading the next batches while the results of
+// the previous ones are written.
+//
+async fn handle_stream_query(
+    socket: &mut TcpStream,
+    hashes: &HashDatabase,
+    algorithm: HashAlgorithm,
+) -> Result<()> {
+    // Without a table the batches are still read, so the connection goes on
+    let table = hashes.get(algorithm);
+
+    info!("Received a query stream.");
+
+    let now = Instant::now();
+
+    let (mut reader, mut writer) = socket.split();
+    let (sender, mut receiver) = mpsc::channel::<Vec<u8>>(STREAM_BACKLOG);
+
+    let read = async move {
+        loop {
+            let hash_count = match reader.read_u16().await {
+                Ok(n) => n,
+                Err(_) => {
+                    error!("Failed to receive length.");
+                    bail!(ProtoError::ReadFail);
+                }
+            };
+            let mut digests = vec![0u8; hash_count as usize * algorithm.digest_size()];
+            if reader.read_exact(&mut digests).await.is_err() {
+                bail!(ProtoError::ReadFail);
+            }
+            // Only fails once writing failed, which reports the error
+            if sender.send(digests).await.is_err() || hash_count == 0 {
+                return Ok(());
+            }
         }
     };
 
-    info!("Received a query with {} hashes.", hash_count);
+    let write = async move {
+        let mut total = 0;
+        while let Some(digests) = receiver.recv().await {
+            let table = match &table {
+                Some(table) => table.snapshot(),
+                None if digests.is_empty() => bail!(ProtoError::UnsupportedAlgorithm),
+                None => continue,
+            };
+            let mut frame: Vec<u8> = vec![ProtoResponseStatus::Success.into(), 0, 0];
+            for digest in digests.chunks_exact(algorithm.digest_size()) {
+                frame.push(table.contains(digest) as u8);
+            }
+            drop(table);
+
+            let hash_count = (frame.len() - 3) as u16;
+            frame[1..3].copy_from_slice(&hash_count.to_be_bytes());
+            writer.write_all(&frame).await?;
+
+            if hash_count == 0 {
+                break;
+            }
+            total += hash_count as usize;
+        }
+        Ok(total)
+    };
+
+    let (_, total) = tokio::try_join!(read, write)?;
+
+    info!("Searched a stream of {} hashes.", total);
+    info!("Total time taken: {:.2?}.", now.elapsed());
+
+    Ok(())
+}
+
+//
+// Serve version 2 frames until the client ends the connection. Every request
+// runs in a task of its own, up to `PIPELINE_DEPTH` at once, & its response
+// is written as soon as it is done, so responses may come out of order.
+//
+// The version byte of the first frame was read by the caller.
+//
+async fn handle_v2(socket: &mut TcpStream, hashes: &HashDatabase) -> Result<()> {
+    info!("Switched connection to protocol version 2.");
 
     let now = Instant::now();
-    let mut results: Vec<u8> = vec![]; 
 
-    let hashes_lock = hashes.lock().await;
+    let (reader, mut writer) = socket.split();
+    let (sender, mut receiver) = mpsc::channel::<(u32, Result<Vec<u8>>)>(PIPELINE_DEPTH);
 
-    for _ in 0..hash_count {
-        let n1 = match socket.read_u64().await {
-            Ok(n) => n,
-            Err(_) => bail!(ERROR_READ_FAIL),
-        };
-        let n2 = match socket.read_u64().await {
-            Ok(n) => n,
-            Err(_) => bail!(ERROR_READ_FAIL),
+    let read = read_v2_requests(reader, hashes, sender);
+
+    let write = async move {
+        let mut total = 0;
+        while let Some((request_id, result)) = receiver.recv().await {
+            let (status, data) = match result {
+                Ok(data) => (ProtoResponseStatus::Success, data),
+                Err(error) => (ProtoResponseStatus::Error, error_data(&error)),
+            };
+            let mut frame = Vec::with_capacity(9 + data.len());
+            frame.extend_from_slice(&request_id.to_be_bytes());
+            frame.push(status.into());
+            frame.extend_from_slice(&(data.len() as u32).to_be_bytes());
+            frame.extend_from_slice(&data);
+            writer.write_all(&frame).await?;
+            total += 1;
+        }
+        Ok(total)
+    };
+
+    let (_, total) = tokio::try_join!(read, write)?;
+
+    info!("Answered {} version 2 requests.", total);
+    info!("Total time taken: {:.2?}.", now.elapsed());
+
+    Ok(())
+}
+
+//
+// Read frames & start their requests until the end command or the end of the
+// connection. Returning drops the sender, so the writer stops once the last
+// running request is answered.
+//
+async fn read_v2_requests(
+    mut reader: ReadHalf<'_>,
+    hashes: &HashDatabase,
+    sender: mpsc::Sender<(u32, Result<Vec<u8>>)>,
+) -> Result<()> {
+    let permits = Arc::new(Semaphore::new(PIPELINE_DEPTH));
+    let mut first = true;
+    loop {
+        if !first {
+            match reader.read_u8().await {
+                Ok(byte) if matches!(ProtoVersion::from(byte), ProtoVersion::V2) => {},
+                Ok(_) => {
+                    error!("Received invalid protocol version.");
+                    bail!(ProtoError::InvalidProtoVersion);
+                },
+                Err(error) if error.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+                Err(_) => bail!(ProtoError::ReadFail),
+            }
+        }
+        first = false;
+
+        let mut header = [0u8; 10];
+        if reader.read_exact(&mut header).await.is_err() {
+            bail!(ProtoError::ReadFail);
+        }
+        let request_id = u32::from_be_bytes([header[0], header[1], header[2], header[3]]);
+        let command = header[4];
+        let flags = header[5];
+        let length = u32::from_be_bytes([header[6], header[7], header[8], header[9]]);
+
+        //
+        // Skip oversized payloads, the following frames are still intact
+        //
+        if length > MAX_PAYLOAD_SIZE {
+            error!("Received a payload of {} bytes.", length);
+            let mut payload = (&mut reader).take(length as u64);
+            if tokio::io::copy(&mut payload, &mut tokio::io::sink()).await.is_err() {
+                bail!(ProtoError::ReadFail);
+            }
+            if sender.send((request_id, Err(anyhow!(ProtoError::PayloadTooLarge)))).await.is_err() {
+                return Ok(());
+            }
+            continue;
+        }
+
+        let mut payload = vec![0u8; length as usize];
+        if reader.read_exact(&mut payload).await.is_err() {
+            bail!(ProtoError::ReadFail);
+        }
+
+        if matches!(ProtoCommand::from(command), ProtoCommand::End) {
+            return Ok(());
+        }
+
+        let permit = match Arc::clone(&permits).acquire_owned().await {
+            Ok(permit) => permit,
+            Err(error) => bail!(error),
         };
+        let hashes = Arc::clone(hashes);
+        let sender = sender.clone();
+        tokio::spawn(async move {
+            let result = handle_v2_request(&hashes, command, flags, payload).await;
+            // Only fails once writing failed, which ends the connection
+            let _ = sender.send((request_id, result)).await;
+            drop(permit);
+        });
+    }
+}
+
+//
+// Run a single version 2 request & return the data of its response.
+//
+async fn handle_v2_request(
+    hashes: &HashDatabase,
+    command: u8,
+    flags: u8,
+    payload: Vec<u8>,
+) -> Result<Vec<u8>> {
+    match ProtoCommand::from(command) {
+        // The flags of a query are its response encoding
+        ProtoCommand::Query => {
+            let encoding = parse_encoding(flags)?;
+            let (algorithm, digests) = parse_v2_digests(hashes, &payload)?;
+            Ok(encode_results(query(hashes, algorithm, digests)?, encoding))
+        },
+        _ if flags != 0 => {
+            error!("Received unknown request flags {:#04x}.", flags);
+            bail!(ProtoError::InvalidFlags);
+        },
+        ProtoCommand::MetadataQuery => {
+            let (algorithm, digests) = parse_v2_digests(hashes, &payload)?;
+            metadata_query(hashes, algorithm, digests)
+        },
+        ProtoCommand::ClassifyQuery => {
+            let (algorithm, digests) = parse_v2_digests(hashes, &payload)?;
+            Ok(classify_query(hashes, algorithm, digests))
+        },
+        ProtoCommand::ListSets => Ok(list_sets(hashes)),
+        ProtoCommand::Update => {
+            let (algorithm, digests) = parse_v2_digests(hashes, &payload)?;
+            let inserted = update(hashes, algorithm, digests).await?;
+            Ok((inserted as u32).to_be_bytes().to_vec())
+        },
+        ProtoCommand::Delete => {
+            let (algorithm, digests) = parse_v2_digests(hashes, &payload)?;
+            let removed = delete(hashes, algorithm, digests).await?;
+            Ok((removed as u32).to_be_bytes().to_vec())
+        },
+        ProtoCommand::Merge => {
+            let merged = merge(hashes).await?;
+            Ok((merged as u32).to_be_bytes().to_vec())
+        },
+        ProtoCommand::Snapshot => {
+            let count = take_snapshot(hashes, parse_name(payload)?).await?;
+            Ok((count as u32).to_be_bytes().to_vec())
+        },
+        ProtoCommand::Restore => {
+            let count = restore_snapshot(hashes, parse_name(payload)?).await?;
+            Ok((count as u32).to_be_bytes().to_vec())
+        },
+        ProtoCommand::Follow | ProtoCommand::Algorithm | ProtoCommand::Encoding
+        | ProtoCommand::End | ProtoCommand::Unknown => {
+            error!("Received invalid version 2 command.");
+            bail!(ProtoError::InvalidCommand);
+        },
+    }
+}
+
+//
+// Split a version 2 payload into its algorithm & packed digests.
+//
+fn parse_v2_digests<'a>(hashes: &HashDatabase, payload: &'a [u8]) -> Result<(HashAlgorithm, &'a [u8])> {
+    let (algorithm, digests) = match payload.split_first() {
+        Some((algorithm, digests)) => (parse_algorithm(hashes, *algorithm)?, digests),
+        None => bail!(ProtoError::InvalidLength),
+    };
+    if digests.len() % algorithm.digest_size() != 0 {
+        error!("Received a partial {} digest.", algorithm.name());
+        bail!(ProtoError::InvalidLength);
+    }
+    Ok((algorithm, digests))
+}
+
+//
+// Map an algorithm byte to a served algorithm.
+//
+fn parse_algorithm(hashes: &HashDatabase, byte: u8) -> Result<HashAlgorithm> {
+    let algorithm = match ProtoAlgorithm::from(byte) {
+        ProtoAlgorithm::Md5 => HashAlgorithm::Md5,
+        ProtoAlgorithm::Sha1 => HashAlgorithm::Sha1,
+        ProtoAlgorithm::Sha256 => HashAlgorithm::Sha256,
+        ProtoAlgorithm::Unknown => {
+            error!("Received invalid hash algorithm.");
+            bail!(ProtoError::InvalidAlgorithm);
+        },
+    };
+
+    if !hashes.algorithms().contains(&algorithm) {
+        error!("Received unsupported hash algorithm \"{}\".", algorithm.name());
+        bail!(ProtoError::UnsupportedAlgorithm);
+    }
+
+    Ok(algorithm)
+}
+
+//
+// Search the default set, one found byte per hash.
+//
+fn query(hashes: &HashDatabase, algorithm: HashAlgorithm, digests: &[u8]) -> Result<Vec<u8>> {
+    let hash_count = digests.len() / algorithm.digest_size();
+
+    info!("Received a query with {} hashes.", hash_count);
+
+    let now = Instant::now();
+    let mut results: Vec<u8> = Vec::with_capacity(hash_count);
+
+    let table = match hashes.get(algorithm) {
+        Some(table) => table.snapshot(),
+        None => bail!(ProtoError::UnsupportedAlgorithm),
+    };
+
+    for digest in digests.chunks_exact(algorithm.digest_size()) {
+        results.push(table.contains(digest) as u8);
+    }
+
+    let elapsed = now.elapsed();
+
+    info!("Total time taken: {:.2?}.", elapsed);
+
+    Ok(results)
+}
+
+fn parse_encoding(byte: u8) -> Result<ProtoEncoding> {
+    match ProtoEncoding::from(byte) {
+        ProtoEncoding::Unknown => {
+            error!("Received invalid response encoding.");
+            bail!(ProtoError::InvalidEncoding);
+        },
+        encoding => Ok(encoding),
+    }
+}
+
+//
+// Encode the found bytes of a query, see the protocol description above.
+// Bytes are sent as they are, other encodings lead with the encoding used.
+//
+fn encode_results(found: Vec<u8>, encoding: ProtoEncoding) -> Vec<u8> {
+    if matches!(encoding, ProtoEncoding::Bytes) {
+        return found;
+    }
 
-        match hashes_lock.binary_search(&(n1, n2)) {
-            Ok(_) => results.push(1),
-            Err(_) => results.push(0),
+    let hits = found.iter().filter(|found| **found != 0).count();
+    let index_size = if found.len() <= INDEX_U16_LIMIT { 2 } else { 4 };
+
+    let encoding = match encoding {
+        // Misses win ties, they are what clients usually look for
+        ProtoEncoding::Smallest => {
+            let bitset_size = found.len().div_ceil(8);
+            let misses_size = 4 + (found.len() - hits) * index_size;
+            let hits_size = 4 + hits * index_size;
+            if misses_size <= hits_size && misses_size <= bitset_size {
+                ProtoEncoding::Misses
+            } else if hits_size <= bitset_size {
+                ProtoEncoding::Hits
+            } else {
+                ProtoEncoding::Bitset
+            }
+        },
+        encoding => encoding,
+    };
+
+    let mut results = vec![u8::from(encoding)];
+    match encoding {
+        ProtoEncoding::Bitset => {
+            results.resize(1 + found.len().div_ceil(8), 0);
+            for (index, _) in found.iter().enumerate().filter(|(_, found)| **found != 0) {
+                results[1 + index / 8] |= 1 << (index % 8);
+            }
+        },
+        ProtoEncoding::Misses | ProtoEncoding::Hits => {
+            let wanted = matches!(encoding, ProtoEncoding::Hits);
+            let count = if wanted { hits } else { found.len() - hits };
+            results.reserve(4 + count * index_size);
+            results.extend_from_slice(&(count as u32).to_be_bytes());
+            for (index, _) in found.iter().enumerate().filter(|(_, found)| (**found != 0) == wanted) {
+                match index_size {
+                    2 => results.extend_from_slice(&(index as u16).to_be_bytes()),
+                    _ => results.extend_from_slice(&(index as u32).to_be_bytes()),
+                }
+            }
+        },
+        _ => {},
+    }
+    results
+}
+
+//
+// Same as a query, but hits also carry their NSRL metadata record.
+//
+fn metadata_query(hashes: &HashDatabase, algorithm: HashAlgorithm, digests: &[u8]) -> Result<Vec<u8>> {
+    info!("Received a metadata query with {} hashes.", digests.len() / algorithm.digest_size());
+
+    let now = Instant::now();
+    let mut results: Vec<u8> = vec![];
+
+    let table = match hashes.get(algorithm) {
+        Some(table) => table.snapshot(),
+        None => bail!(ProtoError::UnsupportedAlgorithm),
+    };
+
+    for digest in digests.chunks_exact(algorithm.digest_size()) {
+        if !table.contains(digest) {
+            results.push(0);
+            continue;
         }
+
+        let record = hashes.metadata()
+            .and_then(|metadata| metadata.record(algorithm, digest))
+            .unwrap_or_default();
+        results.push(1);
+        results.extend_from_slice(&(record.len() as u32).to_be_bytes());
+        results.extend_from_slice(&record);
     }
-    drop(hashes_lock);
 
     let elapsed = now.elapsed();
 
     info!("Total time taken: {:.2?}.", elapsed);
 
-    socket.write_u8(ProtoResponseStatus::Success.into()).await?;
-    socket.write_all(&results).await?;
+    Ok(results)
+}
 
-    Ok(())
+//
+// Check hashes against every named hash set.
+//
+fn classify_query(hashes: &HashDatabase, algorithm: HashAlgorithm, digests: &[u8]) -> Vec<u8> {
+    info!("Received a classify query with {} hashes.", digests.len() / algorithm.digest_size());
+
+    let now = Instant::now();
+    let mut results: Vec<u8> = vec![];
+
+    let mut set_tables = vec![];
+    for (index, set) in hashes.sets().iter().enumerate() {
+        if let Some(table) = set.get(algorithm) {
+            set_tables.push((1u32 << index, set.category().bit(), table.snapshot()));
+        }
+    }
+
+    for digest in digests.chunks_exact(algorithm.digest_size()) {
+        let mut category: u8 = 0;
+        let mut mask: u32 = 0;
+        for (set_bit, category_bit, table) in &set_tables {
+            if table.contains(digest) {
+                category |= category_bit;
+                mask |= set_bit;
+            }
+        }
+        results.push(category);
+        results.extend_from_slice(&mask.to_be_bytes());
+    }
+
+    let elapsed = now.elapsed();
+
+    info!("Total time taken: {:.2?}.", elapsed);
+
+    results
+}
+
+fn list_sets(hashes: &HashDatabase) -> Vec<u8> {
+    let mut results: Vec<u8> = vec![hashes.sets().len() as u8];
+    for set in hashes.sets() {
+        // Names are limited to 255 bytes
+        let name = &set.name().as_bytes()[..set.name().len().min(u8::MAX as usize)];
+        results.push(set.category().bit());
+        results.push(name.len() as u8);
+        results.extend_from_slice(name);
+    }
+    results
 }
 
 //
 // Insert new hashes into in-memory database.
 // Create a change file that contains a sorted list of added hashes.
 //
-// NOTE: This is an expensive operation as it has to shift the entire database 
-// multiple times. For large hash sets it is recommended to use two servers. 
-// One would be the cold storage of for example NSRL, and the other would be the
-// hot storage of newly found hashes. The client would then have to query both.
+// New hashes go to the small delta of the table, which is compacted into the
+// base in the background once it grows, so updates never shift the base.
 //
-async fn handle_v1_update(socket: &mut TcpStream, hashes: &HashDatabase) -> Result<()> {
+async fn update(hashes: &HashDatabase, algorithm: HashAlgorithm, digests: &[u8]) -> Result<usize> {
+    check_leader(hashes)?;
+
+    let hash_count = digests.len() / algorithm.digest_size();
+
+    info!("Received an update with {} hashes.", hash_count);
+
+    let now = Instant::now();
+
+    let inserted = update_table(hashes, algorithm, digests, true).await?;
+
+    info!("Inserted a total of {}/{} hashes.", inserted, hash_count);
+    info!("Hashes that already exist were not inserted.");
+
+    let elapsed = now.elapsed();
+
+    info!("Total time taken: {:.2?}.", elapsed);
+
+    Ok(inserted)
+}
+
+//
+// Remove hashes from in-memory database.
+// Create a change file that contains a sorted list of tombstones, deletion
+// records of the removed hashes, so merging backs them out of the hash file.
+//
+async fn delete(hashes: &HashDatabase, algorithm: HashAlgorithm, digests: &[u8]) -> Result<usize> {
+    check_leader(hashes)?;
+
+    let hash_count = digests.len() / algorithm.digest_size();
+
+    info!("Received a delete with {} hashes.", hash_count);
+
+    let now = Instant::now();
+
+    let removed = update_table(hashes, algorithm, digests, false).await?;
+
+    info!("Removed a total of {}/{} hashes.", removed, hash_count);
+    info!("Hashes that do not exist were not removed.");
+
+    let elapsed = now.elapsed();
+
+    info!("Total time taken: {:.2?}.", elapsed);
+
+    Ok(removed)
+}
+
+//
+// Insert or remove packed digests in the default set & log the actual
+// changes to a change file, which is synced before returning.
+// Returns the number of inserted or removed hashes.
+//
+// Also used by the HTTP API, see `http.rs`.
+//
+pub async fn update_table(
+    hashes: &HashDatabase,
+    algorithm: HashAlgorithm,
+    digests: &[u8],
+    insert: bool,
+) -> Result<usize> {
+    let table = match hashes.get(algorithm) {
+        Some(table) => table,
+        None => bail!(ProtoError::UnsupportedAlgorithm),
+    };
+
+    // Keep merges out until the change file is complete
+    let change_log = hashes.change_log().lock().await;
+
+    let (change_file, change_file_path) = match create_change_file(&hashes.layout().change_file_dir()) {
+        Ok(file) => file,
+        Err(error) => return Err(error),
+    };
+
+    // Existing elements are skipped on insertion, missing ones on removal
+    let mut changed_hashes: Vec<Vec<u8>> = table.update(|table| {
+        digests.chunks_exact(algorithm.digest_size())
+            .filter(|digest| if insert { table.insert(digest) } else { table.remove(digest) })
+            .map(|digest| digest.to_vec())
+            .collect()
+    });
+
+    write_change_file(hashes, change_file, change_file_path, insert, algorithm, &mut changed_hashes)?;
+    drop(change_log);
+
+    schedule_compaction(hashes, algorithm);
+
+    Ok(changed_hashes.len())
+}
+
+//
+// Merge change files into the hash files without restarting, see `merge.rs`.
+// Queries keep being served while merging.
+//
+async fn merge(hashes: &HashDatabase) -> Result<usize> {
+    check_leader(hashes)?;
+
+    info!("Received a merge request.");
+
+    match merge::merge(Arc::clone(hashes)).await {
+        Ok(merged) => Ok(merged),
+        Err(error) => {
+            error!("Failed to merge change files.");
+            error!("{}", error);
+            bail!(ProtoError::MergeFail);
+        }
+    }
+}
+
+//
+// Write a named snapshot of the default set, see `snapshot.rs`.
+//
+async fn take_snapshot(hashes: &HashDatabase, name: String) -> Result<usize> {
+    check_leader(hashes)?;
+
+    info!("Received a snapshot request for \"{}\".", name);
+
+    match snapshot::snapshot(Arc::clone(hashes), name).await {
+        Ok(count) => Ok(count),
+        Err(error) => {
+            error!("Failed to take snapshot.");
+            error!("{}", error);
+            bail!(ProtoError::SnapshotFail);
+        }
+    }
+}
+
+//
+// Restore the default set from a named snapshot, see `snapshot.rs`.
+// Queries keep being served while restoring.
+//
+async fn restore_snapshot(hashes: &HashDatabase, name: String) -> Result<usize> {
+    check_leader(hashes)?;
+
+    info!("Received a restore request for \"{}\".", name);
+
+    match snapshot::restore(Arc::clone(hashes), name).await {
+        Ok(count) => Ok(count),
+        Err(error) => {
+            error!("Failed to restore snapshot.");
+            error!("{}", error);
+            bail!(ProtoError::RestoreFail);
+        }
+    }
+}
+
+//
+// Read a length prefixed snapshot name.
+//
+async fn read_name(socket: &mut TcpStream) -> Result<String> {
+    let length = match socket.read_u8().await {
+        Ok(length) => length,
+        Err(_) => bail!(ProtoError::ReadFail),
+    };
+    let mut name = vec![0u8; length as usize];
+    if socket.read_exact(&mut name).await.is_err() {
+        bail!(ProtoError::ReadFail);
+    }
+
+    parse_name(name)
+}
+
+fn parse_name(name: Vec<u8>) -> Result<String> {
+    match String::from_utf8(name) {
+        Ok(name) if snapshot::is_valid_name(&name) => Ok(name),
+        _ => {
+            error!("Received invalid snapshot name.");
+            bail!(ProtoError::InvalidName);
+        }
+    }
+}
+
+//
+// Read the count & hashes of a version 1 command.
+//
+async fn read_v1_digests(socket: &mut TcpStream, algorithm: HashAlgorithm) -> Result<Vec<u8>> {
     let hash_count = match socket.read_u16().await {
         Ok(n) => n,
         Err(_) => {
             error!("Failed to receive length.");
-            bail!(ERROR_INVALID_LENGTH);
+            bail!(ProtoError::ReadFail);
         }
     };
 
-    info!("Received an update with {} hashes.", hash_count);
+    read_digests(socket, hash_count, algorithm).await
+}
 
-    let (mut change_file, change_file_path) = match create_change_file() {
-        Ok(file) => file,
-        Err(error) => bail!(error),
-    };
+//
+// Reject writes & admin commands on followers, see `replication.rs`.
+//
+fn check_leader(hashes: &HashDatabase) -> Result<()> {
+    if hashes.is_read_only() {
+        error!("Received a write or admin command on a follower.");
+        bail!(ProtoError::ReadOnly);
+    }
+    Ok(())
+}
 
-    let now = Instant::now();
+fn error_code(error: &anyhow::Error) -> ProtoError {
+    error.downcast_ref::<ProtoError>().copied().unwrap_or(ProtoError::Internal)
+}
 
-    let mut new_hashes: Vec<(u64, u64)> = vec![]; 
-    let mut hashes_lock = hashes.lock().await;
+//
+// Code & length prefixed message of an error, messages of other errors than
+// `ProtoError` are sent as they are.
+//
+fn error_data(error: &anyhow::Error) -> Vec<u8> {
+    let message = error.to_string();
+    let message = &message.as_bytes()[..message.len().min(u16::MAX as usize)];
 
-    for _ in 0..hash_count {
-        let n1 = match socket.read_u64().await {
-            Ok(n) => n,
-            Err(_) => bail!(ERROR_READ_FAIL),
-        };
-        let n2 = match socket.read_u64().await {
-            Ok(n) => n,
-            Err(_) => bail!(ERROR_READ_FAIL),
-        };
+    let mut data = Vec::with_capacity(4 + message.len());
+    data.extend_from_slice(&u16::from(error_code(error)).to_be_bytes());
+    data.extend_from_slice(&(message.len() as u16).to_be_bytes());
+    data.extend_from_slice(message);
+    data
+}
 
-        match hashes_lock.binary_search(&(n1, n2)) {
-            Ok(_) => {}, // Element exists
-            Err(pos) => {
-                hashes_lock.insert(pos, (n1, n2));
-                new_hashes.push((n1, n2));
-            },
-        }
+fn error_response(error: &anyhow::Error) -> Vec<u8> {
+    let mut response = vec![ProtoResponseStatus::Error.into()];
+    response.extend_from_slice(&error_data(error));
+    response
+}
+
+//
+// Describe the code & message following an error status, as received by
+// the snapshot subcommands & followers.
+//
+pub fn decode_error(data: &[u8]) -> String {
+    if data.len() < 4 {
+        return "Got truncated error response.".to_owned();
     }
-    drop(hashes_lock);
+    let code = u16::from_be_bytes([data[0], data[1]]);
+    let length = u16::from_be_bytes([data[2], data[3]]) as usize;
+    let message = &data[4..data.len().min(4 + length)];
+    format!("{} (error code {})", String::from_utf8_lossy(message), code)
+}
 
-    info!("Inserted a total of {}/{} hashes.", new_hashes.len(), hash_count);
-    info!("Hashes that already exist were not inserted.");
+//
+// Fold a grown delta into the base on a blocking thread, so neither this
+// connection nor the runtime waits for it.
+//
+pub fn schedule_compaction(hashes: &HashDatabase, algorithm: HashAlgorithm) {
+    if hashes.get(algorithm).is_some_and(|table| table.needs_compaction()) {
+        let hashes = hashes.clone();
+        tokio::task::spawn_blocking(move || {
+            if let Some(table) = hashes.get(algorithm) {
+                table.compact();
+            }
+        });
+    }
+}
 
-    if new_hashes.len() != 0 {
-        new_hashes.sort();
-        for hash in &new_hashes {
-            match change_file.write_fmt(
-                format_args!("{:016X}{:016X}\n", hash.0, hash.1)) {
-                Ok(_) => {},
-                Err(error) => {
-                    error!("Failed to write change file.");
-                    error!("{}", error);
-                    bail!(ERROR_CHANGE_FILE_WRITE_FAIL);
-                }
-            };
-        }
+//
+// Read all hashes of a command before touching any table, so a slow client
+// never holds up other connections.
+//
+async fn read_digests(
+    socket: &mut TcpStream,
+    hash_count: u16,
+    algorithm: HashAlgorithm,
+) -> Result<Vec<u8>> {
+    let mut digests = vec![0u8; hash_count as usize * algorithm.digest_size()];
+    if socket.read_exact(&mut digests).await.is_err() {
+        bail!(ProtoError::ReadFail);
+    }
+    Ok(digests)
+}
+
+//
+// Write the changed hashes sorted as change file records & sync them, see
+// `wal.rs`, then send the records to followers. The change file is removed
+// instead if nothing changed.
+//
+fn write_change_file(
+    hashes: &HashDatabase,
+    mut change_file: File,
+    change_file_path: String,
+    insert: bool,
+    algorithm: HashAlgorithm,
+    changed_hashes: &mut [Vec<u8>],
+) -> Result<()> {
+    if !changed_hashes.is_empty() {
+        changed_hashes.sort();
+        match wal::write_change_file(&hashes.layout().change_file_dir(), &mut change_file, insert, algorithm, changed_hashes) {
+            Ok(_) => {},
+            Err(error) => {
+                error!("Failed to write change file.");
+                error!("{}", error);
+                bail!(ProtoError::ChangeFileWriteFail);
+            }
+        };
 
         info!("Wrote change to \"{}\".", change_file_path);
+
+        let mut records = vec![];
+        wal::encode_records(insert, algorithm, changed_hashes, &mut records);
+        hashes.replicate(Replication::Changes(Arc::new(records)));
     } else {
         drop(change_file);
         match fs::remove_file(change_file_path) {
@@ -276,32 +1505,25 @@ async fn handle_v1_update(socket: &mut TcpStream, hashes: &HashDatabase) -> Resu
             Err(error) => {
                 error!("Failed to remove empty change file.");
                 error!("{}", error);
-                bail!(ERROR_CHANGE_FILE_REMOVE_FAIL);
+                bail!(ProtoError::ChangeFileRemoveFail);
             }
         };
-        info!("No new hashes added, change file not created.");
+        info!("Nothing changed, change file not created.");
     }
 
-    let elapsed = now.elapsed();
-
-    info!("Total time taken: {:.2?}.", elapsed);
-
-    socket.write_u8(ProtoResponseStatus::Success.into()).await?;
-    socket.write_u16(new_hashes.len() as u16).await?;
-
     Ok(())
 }
 
-fn create_change_file() -> Result<(File, String)> {
+fn create_change_file(directory: &Path) -> Result<(File, String)> {
     //
     // Check for change file directory
     // Create if missing
     //
-    match fs::exists(globals::CHANGE_FILE_DIR) {
+    match fs::exists(directory) {
         Ok(exists) => {
             if !exists {
                 info!("Change file directory missing.");
-                match fs::create_dir(globals::CHANGE_FILE_DIR) {
+                match fs::create_dir_all(directory) {
                     Ok(_) => info!("Created change file directory."),
                     Err(error) => {
                         error!("Failed to create change file directory.");
@@ -313,7 +1535,7 @@ fn create_change_file() -> Result<(File, String)> {
         Err(error) => {
             error!("Failed to check change file directory.");
             error!("{}", error);
-            bail!(ERROR_CHANGE_DIR_CHECK_FAIL);
+            bail!(ProtoError::ChangeDirCheckFail);
         }
     }
     //
@@ -324,18 +1546,17 @@ fn create_change_file() -> Result<(File, String)> {
         Err(error) => {
             error!("Failed to generate change file name.");
             error!("{}", error);
-            bail!(ERROR_CHANGE_FILE_CREATE_FAIL);
+            bail!(ProtoError::ChangeFileCreateFail);
         }
     };
-    let change_file_path = globals::CHANGE_FILE_DIR.to_owned() + 
-        "/" + &change_file_name;
+    let change_file_path = directory.join(change_file_name).display().to_string();
 
     match File::create(&change_file_path) {
         Ok(file) => Ok((file, change_file_path)),
         Err(error) => {
             error!("Failed to open change file.");
             error!("{}", error);
-            bail!(ERROR_CHANGE_FILE_CREATE_FAIL);
+            bail!(ProtoError::ChangeFileCreateFail);
         }
     }
 }
@@ -0,0 +1,262 @@
+use anyhow::{Result, bail};
+
+use std::io::ErrorKind;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use log::{info, warn, error};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::database::HashTable;
+use crate::hash::HashAlgorithm;
+use crate::proto::{self, HashDatabase};
+use crate::wal::{self, Change};
+
+//
+// Replication of the default set from a leader to followers.
+//
+// Every server is a leader. A follower (`--follow`) connects to it & sends
+// the follow command, see `proto.rs`. The leader answers with the current
+// contents of the default set, base & runtime changes:
+//
+// +--------+--------+
+// | Status | Count  |
+// +--------+--------+
+// | 1 byte | 1 byte |
+// +--------+--------+
+//
+// Followed by Count tables:
+//
+// +-----------+-------+------------------------------+
+// | Algorithm | Count |           Digests            |
+// +-----------+-------+------------------------------+
+// | 1 byte    | u64be | Count * digest size bytes    |
+// +-----------+-------+------------------------------+
+//
+// It then streams every update & deletion as change file records, see
+// `wal.rs`, in the order they were written to change files. The tables are
+// taken holding the change log, so the stream starts right where they end.
+//
+// The leader drops a follower that falls more than `REPLICATION_BACKLOG`
+// events behind, as well as all followers once a snapshot is restored.
+// A follower then reconnects & starts over with the full tables, the same
+// as after any other connection loss. Followers keep nothing on disk.
+//
+
+pub const REPLICATION_BACKLOG: usize = 1024;
+
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+#[derive(Clone)]
+pub enum Replication {
+    // Change file records of a single command
+    Changes(Arc<Vec<u8>>),
+    // The default set was replaced, followers have to start over
+    Reset,
+}
+
+//
+// Send the default set to a follower, then stream changes until either side
+// ends the connection. The status byte is written by the caller.
+//
+pub async fn serve_follower(socket: &mut TcpStream, hashes: &HashDatabase) -> Result<()> {
+    let now = Instant::now();
+
+    let (mut events, snapshots) = {
+        let _change_log = hashes.change_log().lock().await;
+        let set = &hashes.sets()[0];
+        let snapshots: Vec<_> = set.algorithms().into_iter()
+            .filter_map(|algorithm| set.get(algorithm).map(|table| table.snapshot()))
+            .collect();
+        (hashes.subscribe(), snapshots)
+    };
+
+    // Applying the changes copies the tables, keep it off the runtime
+    let tables = match tokio::task::spawn_blocking(move || {
+        snapshots.iter().map(|snapshot| snapshot.to_table()).collect::<Vec<HashTable>>()
+    }).await {
+        Ok(tables) => tables,
+        Err(error) => bail!(error),
+    };
+
+    socket.write_u8(tables.len() as u8).await?;
+    for table in &tables {
+        socket.write_u8(table.algorithm().id()).await?;
+        socket.write_u64(table.len() as u64).await?;
+        socket.write_all(table.as_bytes()).await?;
+        info!("Sent {} {} hashes to follower.", table.len(), table.algorithm().name());
+    }
+    drop(tables);
+
+    info!("Follower is in sync, streaming changes.");
+    info!("Total time taken: {:.2?}.", now.elapsed());
+
+    let mut byte = [0u8; 1];
+    loop {
+        tokio::select! {
+            event = events.recv() => match event {
+                Ok(Replication::Changes(records)) => socket.write_all(&records).await?,
+                Ok(Replication::Reset) => {
+                    info!("Hash set was replaced, disconnecting follower to start over.");
+                    return Ok(());
+                },
+                Err(RecvError::Lagged(count)) => {
+                    warn!("Follower fell {} changes behind, disconnecting it to start over.", count);
+                    return Ok(());
+                },
+                Err(RecvError::Closed) => return Ok(()),
+            },
+            // Followers send nothing after the follow command, so a read
+            // only completes once they disconnect
+            _ = socket.read(&mut byte) => {
+                info!("Follower disconnected.");
+                return Ok(());
+            },
+        }
+    }
+}
+
+//
+// Connect to a leader & receive its default set, retrying until it answers.
+//
+pub async fn sync(leader: &str) -> (BufReader<TcpStream>, Vec<HashTable>) {
+    loop {
+        match connect(leader).await {
+            Ok(result) => return result,
+            Err(error) => {
+                error!("Failed to sync with leader \"{}\", retrying in {:?}.", leader, RECONNECT_DELAY);
+                error!("{}", error);
+                tokio::time::sleep(RECONNECT_DELAY).await;
+            },
+        }
+    }
+}
+
+async fn connect(leader: &str) -> Result<(BufReader<TcpStream>, Vec<HashTable>)> {
+    info!("Syncing with leader \"{}\".", leader);
+
+    let mut socket = BufReader::new(TcpStream::connect(leader).await?);
+    socket.get_mut().write_all(b"1f").await?;
+
+    match socket.read_u8().await? {
+        b's' => {},
+        b'e' => {
+            // The leader keeps the connection open after an error
+            let mut error = vec![0u8; 4];
+            socket.read_exact(&mut error).await?;
+            error.resize(4 + u16::from_be_bytes([error[2], error[3]]) as usize, 0);
+            socket.read_exact(&mut error[4..]).await?;
+            bail!("Leader responded with \"{}\".", proto::decode_error(&error));
+        },
+        _ => bail!("Got invalid response from leader."),
+    }
+
+    let mut tables = vec![];
+    for _ in 0..socket.read_u8().await? {
+        let algorithm = match HashAlgorithm::from_id(socket.read_u8().await?) {
+            Some(algorithm) => algorithm,
+            None => bail!("Got invalid algorithm from leader."),
+        };
+        let count = socket.read_u64().await? as usize;
+        let mut digests = vec![0u8; count * algorithm.digest_size()];
+        socket.read_exact(&mut digests).await?;
+
+        info!("Received {} {} hashes from leader.", count, algorithm.name());
+        tables.push(HashTable::from_packed(algorithm, digests));
+    }
+
+    Ok((socket, tables))
+}
+
+//
+// Apply the changes streamed by the leader in the background, syncing again
+// whenever the connection is lost.
+//
+pub fn follow(hashes: HashDatabase, leader: String, mut socket: BufReader<TcpStream>) {
+    tokio::spawn(async move {
+        loop {
+            match apply_changes(&hashes, &mut socket).await {
+                Ok(_) => warn!("Leader \"{}\" closed the connection.", leader),
+                Err(error) => {
+                    error!("Lost connection to leader \"{}\".", leader);
+                    error!("{}", error);
+                },
+            }
+
+            let tables;
+            (socket, tables) = sync(&leader).await;
+            for table in tables {
+                match hashes.get(table.algorithm()) {
+                    Some(shared) => shared.replace(table),
+                    None => warn!("Ignoring {} hashes of leader, not served since startup.",
+                        table.algorithm().name()),
+                }
+            }
+            info!("Synced with leader \"{}\" again.", leader);
+        }
+    });
+}
+
+//
+// Apply records until the leader closes the connection. Records that arrive
+// together are applied together, so a burst of changes publishes a single
+// new snapshot per table.
+//
+async fn apply_changes(hashes: &HashDatabase, socket: &mut BufReader<TcpStream>) -> Result<()> {
+    let mut changes: Vec<Change> = vec![];
+    let mut record = vec![];
+    loop {
+        let op = match socket.read_u8().await {
+            Ok(op) => op,
+            Err(error) if error.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+            Err(error) => bail!(error),
+        };
+        let algorithm = match HashAlgorithm::from_id(socket.read_u8().await?) {
+            Some(algorithm) => algorithm,
+            None => bail!("Got invalid algorithm from leader."),
+        };
+
+        record.clear();
+        record.extend_from_slice(&[op, algorithm.id()]);
+        record.resize(wal::record_size(algorithm), 0);
+        socket.read_exact(&mut record[2..]).await?;
+
+        match wal::decode_record(&record) {
+            Some((change, _)) => changes.push(change),
+            None => bail!("Got corrupt change record from leader."),
+        }
+
+        if socket.buffer().is_empty() {
+            apply(hashes, &changes)?;
+            changes.clear();
+        }
+    }
+}
+
+fn apply(hashes: &HashDatabase, changes: &[Change]) -> Result<()> {
+    let mut algorithms: Vec<HashAlgorithm> = changes.iter().map(|change| change.algorithm).collect();
+    algorithms.sort_by_key(|algorithm| algorithm.id());
+    algorithms.dedup();
+
+    for algorithm in algorithms {
+        let table = match hashes.get(algorithm) {
+            Some(table) => table,
+            None => bail!("Got {} changes from leader but no {} hashes are served.",
+                algorithm.name(), algorithm.name()),
+        };
+        table.update(|table| {
+            for change in changes.iter().filter(|change| change.algorithm == algorithm) {
+                match change.insert {
+                    true => table.insert(&change.digest),
+                    false => table.remove(&change.digest),
+                };
+            }
+        });
+        proto::schedule_compaction(hashes, algorithm);
+    }
+
+    Ok(())
+}
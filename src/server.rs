@@ -1,38 +1,65 @@
-use anyhow::{Result, Error, bail};
+use anyhow::{Result, bail};
 
+use std::fs::File;
 use std::path::Path;
-use std::fs::{self, DirEntry};
-use std::time::Instant;
+use std::time::{Duration, Instant};
 use std::sync::Arc;
-use std::io::Write;
-use std::vec;
 
 use log::{set_logger, set_max_level, LevelFilter};
-use log::{info, error, warn};
+use log::{info, error};
 
 use tokio::net::TcpListener;
-use tokio::sync::Mutex;
 
 use crate::utils;
 use crate::logger;
 use crate::args;
 use crate::globals;
 use crate::proto;
+use crate::ingest::{self, HashFile};
+use crate::index;
+use crate::layout::Layout;
+use crate::merge;
+use crate::http;
+use crate::nsrl;
+use crate::replication;
+use crate::snapshot;
+use crate::wal;
+use crate::database::{Category, Database, HashTable, MAX_SETS, NamedSet};
+use crate::metadata::Metadata;
+use crate::hash::{self, HashAlgorithm, MAX_DIGEST_SIZE};
 
 const LOGGER: logger::Logger = logger::Logger; 
-const MD5_SIZE: usize = 32;
 
+//
+// A named hash set given on the command line,
+// `name:category:[algorithm:]path`, repeated once per algorithm.
+//
+struct HashSetFiles {
+    name: String,
+    category: Category,
+    files: Vec<HashFile>,
+}
 
 pub struct Server {
     args: args::Args,
+    hash_files: Vec<HashFile>,
+    hash_sets: Vec<HashSetFiles>,
     hashes: proto::HashDatabase,
+    layout: Layout,
+    // Lock of the data directory, held until the server exits
+    _lock: Option<File>,
 }
 
 impl Server {
     pub fn new(args: args::Args) -> Self {
+        let layout = Layout::new(&args.data_dir);
         Server {
             args,
-            hashes: Arc::new(Mutex::new(vec![])),
+            hash_files: vec![],
+            hash_sets: vec![],
+            hashes: Arc::new(Database::new()),
+            layout,
+            _lock: None,
         }
     }
 
@@ -50,18 +77,83 @@ impl Server {
 
         set_logger(&LOGGER).map(|()| set_max_level(level)).unwrap();
 
+        //
+        // If a subcommand is given, run it & exit
+        //
+        if let Some(command) = &self.args.command {
+            match Self::run_command(command) {
+                Ok(_) => std::process::exit(0),
+                Err(e) => {
+                    error!("Failed to run command.");
+                    error!("{}", e);
+                    std::process::exit(1);
+                },
+            }
+        }
+
         //
         // Verify that passed command line is somewhat valid
         //
         self.verify_cmdline();
 
         //
-        // Initialize DB or merge
-        // Note that merge initializes DB while merging
+        // Followers only hold the replicated hash set in memory
+        //
+        if !self.args.follow.is_empty() {
+            match self.initialize_follower().await {
+                Ok(_) => {},
+                Err(e) => {
+                    error!("Failed to initialize \"duhashtsrv\".");
+                    error!("{}", e);
+                    std::process::exit(1);
+                },
+            }
+            self.run().await;
+            return;
+        }
+
+        //
+        // Lock the data directory, so no other server uses it
+        //
+        match self.layout.create_and_lock() {
+            Ok(lock) => self._lock = lock,
+            Err(e) => {
+                error!("Failed to lock data directory.");
+                error!("{}", e);
+                std::process::exit(1);
+            },
+        }
+
+        //
+        // Truncate change files torn by a crash
+        //
+        match wal::recover(&self.layout.change_file_dir()) {
+            Ok(_) => {},
+            Err(e) => {
+                error!("Failed to recover change files.");
+                error!("{}", e);
+                std::process::exit(1);
+            },
+        }
+
+        //
+        // Initialize DB
+        //
+        match self.initialize() {
+            Ok(_) => {},
+            Err(e) => {
+                error!("Failed to initialize \"duhashtsrv\".");
+                error!("{}", e);
+                std::process::exit(1);
+            },
+        }
+
+        //
+        // Merge change files before serving, same as an online merge,
+        // otherwise replay them in memory only
         //
         if self.args.merge {
-            // Merge and initialize
-            match self.merge_and_initialize() {
+            match merge::merge(Arc::clone(&self.hashes)).await {
                 Ok(_) => {},
                 Err(e) => {
                     error!("Failed to merge and initialize \"duhashtsrv\".");
@@ -70,21 +162,27 @@ impl Server {
                 },
             }
         } else {
-            // Initialize normally
-            match self.initialize() {
+            match merge::replay_change_files(&self.hashes) {
                 Ok(_) => {},
                 Err(e) => {
-                    error!("Failed to initialize \"duhashtsrv\".");
+                    error!("Failed to replay change files.");
                     error!("{}", e);
                     std::process::exit(1);
                 },
             }
         }
 
+        self.run().await;
+    }
+
+    //
+    // Run the test if one is given, otherwise serve.
+    //
+    async fn run(&self) {
         //
         // If the test is defined, run test & exit
         //
-        if self.args.test.len() != 0 {
+        if !self.args.test.is_empty() {
             match self.test() {
                 Ok(_) => {
                     std::process::exit(0);
@@ -107,66 +205,156 @@ impl Server {
         }
     }
 
-    fn verify_cmdline(&self) {
-        let exists = Path::new(&self.args.hash_file).exists();
+    fn verify_cmdline(&mut self) {
+        // Value is restricted by the argument parser
+        let default_algorithm = HashAlgorithm::from_name(&self.args.hash_type)
+            .unwrap_or(HashAlgorithm::Md5);
+
+        for argument in &self.args.hash_file {
+            let hash_file = Self::parse_hash_file(argument, default_algorithm);
+
+            if self.hash_files.iter().any(|file| file.algorithm == hash_file.algorithm) {
+                error!("Failed to start \"duhashtsrv\".");
+                error!("Got more than one {} hash file.", hash_file.algorithm.name());
+                std::process::exit(1);
+            }
+
+            self.hash_files.push(hash_file);
+        }
+
+        for argument in &self.args.hash_set {
+            let (name, category, file) = match argument.splitn(3, ':').collect::<Vec<&str>>()[..] {
+                [name, category, file] => (name, category, file),
+                _ => {
+                    error!("Failed to start \"duhashtsrv\".");
+                    error!("Given hash set \"{}\" is not \"name:category:path\".", argument);
+                    std::process::exit(1);
+                },
+            };
+            let category = match Category::from_name(category) {
+                Some(category) => category,
+                None => {
+                    error!("Failed to start \"duhashtsrv\".");
+                    error!("Given hash set category \"{}\" is not \"known-good\" or \"known-bad\".",
+                        category);
+                    std::process::exit(1);
+                },
+            };
+            let hash_file = Self::parse_hash_file(file, default_algorithm);
+
+            if name == globals::DEFAULT_SET_NAME {
+                error!("Failed to start \"duhashtsrv\".");
+                error!("Hash set name \"{}\" is reserved for \"--hash-file\".", name);
+                std::process::exit(1);
+            }
 
-        if !exists {
+            match self.hash_sets.iter_mut().find(|set| set.name == name) {
+                Some(set) => {
+                    if set.category != category {
+                        error!("Failed to start \"duhashtsrv\".");
+                        error!("Hash set \"{}\" given with different categories.", name);
+                        std::process::exit(1);
+                    }
+                    if set.files.iter().any(|file| file.algorithm == hash_file.algorithm) {
+                        error!("Failed to start \"duhashtsrv\".");
+                        error!("Got more than one {} hash file for hash set \"{}\".",
+                            hash_file.algorithm.name(), name);
+                        std::process::exit(1);
+                    }
+                    set.files.push(hash_file);
+                },
+                None => self.hash_sets.push(HashSetFiles {
+                    name: name.to_owned(),
+                    category,
+                    files: vec![hash_file],
+                }),
+            }
+        }
+
+        // The default set takes one slot
+        if self.hash_sets.len() + 1 > MAX_SETS {
             error!("Failed to start \"duhashtsrv\".");
-            error!("Given hash file \"{}\" not found.", &self.args.hash_file);
+            error!("Got more than {} hash sets.", MAX_SETS - 1);
             std::process::exit(1);
         }
 
-        if self.args.test.len() != 0 && self.args.test.len() != MD5_SIZE {
+        let hash_files = self.hash_files.iter()
+            .chain(self.hash_sets.iter().flat_map(|set| set.files.iter()));
+        for hash_file in hash_files {
+            // Base indexes of the data directory stand in for their hash files
+            let base_path = self.layout.base_path(hash_file.algorithm)
+                .filter(|_| self.hash_files.iter().any(|file| file.path == hash_file.path));
+            if base_path.is_some_and(|path| path.exists()) {
+                continue;
+            }
+            if !Path::new(&hash_file.path).exists() {
+                error!("Failed to start \"duhashtsrv\".");
+                error!("Given hash file \"{}\" not found.", &hash_file.path);
+                std::process::exit(1);
+            }
+        }
+
+        if !self.args.metadata.is_empty() && !Path::new(&self.args.metadata).exists() {
             error!("Failed to start \"duhashtsrv\".");
-            error!("Given test hash \"{}\" does not match 32 bytes.", &self.args.test);
+            error!("Given metadata file \"{}\" not found.", &self.args.metadata);
             std::process::exit(1);
         }
 
-        if !self.args.merge && Self::has_change_files() {
-            warn!("Found change files in \"./{}/\".", globals::CHANGE_FILE_DIR);
-            warn!("Contents will not be used.");
-            warn!("Use \"--merge\" parameter to merge into database.");
-            warn!("Note that \"--merge\" will update the on-disk hash file.");
+        // The algorithms of a follower are only known once it synced
+        if !self.args.test.is_empty() && self.args.follow.is_empty() {
+            let served = HashAlgorithm::from_hex_size(self.args.test.len())
+                .is_some_and(|algorithm| {
+                    self.hash_files.iter()
+                        .chain(self.hash_sets.iter().flat_map(|set| set.files.iter()))
+                        .any(|file| file.algorithm == algorithm)
+                });
+            if !served {
+                error!("Failed to start \"duhashtsrv\".");
+                error!("Given test hash \"{}\" does not match any loaded hash file.",
+                    &self.args.test);
+                std::process::exit(1);
+            }
         }
+
     } 
 
     //
-    // Initialize the database, by reading the input file & pulling all hashes
-    // in memory as u64 pairs.
+    // Parse `[algorithm:]path`.
+    //
+    fn parse_hash_file(argument: &str, default_algorithm: HashAlgorithm) -> HashFile {
+        match argument.split_once(':') {
+            Some((prefix, path)) => match HashAlgorithm::from_name(prefix) {
+                Some(algorithm) => HashFile { algorithm, path: path.to_owned() },
+                None => HashFile { algorithm: default_algorithm, path: argument.to_owned() },
+            },
+            None => HashFile { algorithm: default_algorithm, path: argument.to_owned() },
+        }
+    }
+
+    //
+    // Initialize the database, by reading the input files & pulling all hashes
+    // in memory as packed digests, one index per algorithm.
     //
     fn initialize(&mut self) -> Result<()> {
         info!("Initializing \"duhashtsrv\" version {}.", globals::VERSION);
 
-        let ingest_size: u64 = utils::get_size(self.args.hash_file.clone())?;
-        let line_amount: usize = ingest_size as usize / (MD5_SIZE + 1);
-
-        info!("Got ingest size: {} bytes.", ingest_size);
-        info!("Calculated total: {} MD5 hashes.", line_amount);
-
-        self.hashes = Arc::new(Mutex::new(Vec::with_capacity(line_amount)));
-        let hashes: proto::HashDatabase = Arc::clone(&self.hashes);
-        let mut hashes_lock = hashes.try_lock().unwrap();
-
         let now = Instant::now();
 
-        if let Ok(lines) = utils::read_lines(self.args.hash_file.clone()) {
-            for line in lines.map_while(Result::ok) {
-                if line.len() != MD5_SIZE {
-                    bail!("Got invalid hash, size > {} bytes.", MD5_SIZE);
-                }
-
-                let n1 = match u64::from_str_radix(&line[..16], 16) {
-                    Ok(n) => n,
-                    Err(_) => bail!("Failed to parse \"{}\" as pair u64.", line),
-                };
-                let n2 = match u64::from_str_radix(&line[16..], 16) {
-                    Ok(n) => n,
-                    Err(_) => bail!("Failed to parse \"{}\" as pair u64.", line),
-                };
-
-                hashes_lock.push((n1, n2));
-            }
+        let mut database = Database::new();
+        let mut files = vec![];
+        for hash_file in &self.hash_files {
+            let hash_file = match self.layout.base_path(hash_file.algorithm) {
+                Some(base_path) => self.import_hash_file(hash_file, &base_path)?,
+                None => hash_file.clone(),
+            };
+            database.add(self.read_hash_file(&hash_file)?);
+            files.push(hash_file);
         }
+        database.set_files(files, self.read_options());
+        database.set_layout(self.layout.clone());
+        self.load_hash_sets(&mut database)?;
+        self.load_metadata(&mut database)?;
+        self.hashes = Arc::new(database);
 
         let elapsed = now.elapsed();
 
@@ -177,166 +365,142 @@ impl Server {
     }
 
     //
-    // Merge and initialize.
-    // Creates a backup of `hash_file`.
-    // Parses all files in `globas::CHANGE_FILE_DIR` as (u64, u64).
-    // Reads & parses `hash_file` in memory as (u64, u64).
-    // Inserts all change file hashes.
-    // Writes new database to `hash_file` & removes change files.
+    // Initialize the database from the default set of a leader & keep
+    // following its changes, see `replication.rs`.
     //
-    fn merge_and_initialize(&mut self) -> Result<()> {
-        // Check for files
-        // Initialize normally otherwise
-        if !Self::has_change_files() {
-            info!("Merge specified but no change files found.");
-            match self.initialize() {
-                Ok(_) => {},
-                Err(e) => {
-                    error!("Failed to initialize \"duhashtsrv\".");
-                    error!("{}", e);
-                    std::process::exit(1);
-                },
-            }
-            return Ok(());
-        }
+    async fn initialize_follower(&mut self) -> Result<()> {
+        info!("Initializing \"duhashtsrv\" version {} as follower of \"{}\".",
+            globals::VERSION, self.args.follow);
 
         let now = Instant::now();
 
-        //
-        // Backup existing file
-        //
-        info!("Initializing \"duhashtsrv\" version {} with merge.", globals::VERSION);
-        info!("Creating backup of the existing \"{}\" hash file.", self.args.hash_file);
-        self.backup_hash_file()?;
+        let (socket, tables) = replication::sync(&self.args.follow).await;
 
-        //
-        // Read & parse all change files
-        //
-        info!("Parsing change files.");
-        let mut new_hashes: Vec<(u64, u64)> = vec![]; 
-        let paths = Self::get_change_file_paths()?;
-        for path in &paths {
+        let mut database = Database::new();
+        for table in tables {
+            database.add(table);
+        }
+        database.set_read_only(true);
+        self.load_hash_sets(&mut database)?;
+        self.load_metadata(&mut database)?;
+        self.hashes = Arc::new(database);
 
-            info!("Parsing change file \"./{}\".", path.path().display());
+        replication::follow(Arc::clone(&self.hashes), self.args.follow.clone(), socket);
 
-            if let Ok(lines) = utils::read_lines(path.path()) {
-                for line in lines.map_while(Result::ok) {
-                    if line.len() != MD5_SIZE {
-                        bail!("Got invalid hash, size > {} bytes.", MD5_SIZE);
-                    }
+        let elapsed = now.elapsed();
 
-                    let n1 = match u64::from_str_radix(&line[..16], 16) {
-                        Ok(n) => n,
-                        Err(_) => bail!("Failed to parse \"{}\" as pair u64.", line),
-                    };
-                    let n2 = match u64::from_str_radix(&line[16..], 16) {
-                        Ok(n) => n,
-                        Err(_) => bail!("Failed to parse \"{}\" as pair u64.", line),
-                    };
+        info!("Finished syncing hashes.");
+        info!("Total time taken: {:.2?}.", elapsed);
 
-                    new_hashes.push((n1, n2));
-                }
-            }
-        }
-        info!("Finished parsing change files.");
-        info!("Got total of {} hashes.", new_hashes.len());
+        Ok(())
+    }
 
+    //
+    // Import a hash file of the default set into its base index in the data
+    // directory, unless the base index exists already. Merges then rewrite
+    // the base index, never the given hash file.
+    //
+    fn import_hash_file(&self, hash_file: &HashFile, base_path: &Path) -> Result<HashFile> {
+        let base = HashFile {
+            algorithm: hash_file.algorithm,
+            path: base_path.display().to_string(),
+        };
 
-        //
-        // Read the current hash file as database
-        //
-        info!("Parsing the existing hash database.");
+        if base_path.exists() {
+            info!("Using {} base index \"{}\" instead of \"{}\".",
+                base.algorithm.name(), base.path, hash_file.path);
+            return Ok(base);
+        }
 
-        let ingest_size: u64 = utils::get_size(self.args.hash_file.clone())?;
-        let line_amount: usize = ingest_size as usize / (MD5_SIZE + 1);
+        info!("Importing {} hash file \"{}\" into base index \"{}\".",
+            hash_file.algorithm.name(), hash_file.path, base.path);
 
-        info!("Got ingest size: {} bytes.", ingest_size);
-        info!("Calculated total: {} MD5 hashes.", line_amount);
+        let options = ingest::ReadOptions { mmap: false, ..self.read_options() };
+        let hashes = ingest::read_hash_file(hash_file.algorithm, &hash_file.path, &options)?;
+        merge::write_hash_file(&base, &hashes, true)?;
 
-        self.hashes = Arc::new(Mutex::new(Vec::with_capacity(line_amount)));
-        let hashes: proto::HashDatabase = Arc::clone(&self.hashes);
-        let mut hashes_lock = hashes.try_lock().unwrap();
+        info!("Imported {} hashes.", hashes.len());
 
-        if let Ok(lines) = utils::read_lines(self.args.hash_file.clone()) {
-            for line in lines.map_while(Result::ok) {
-                if line.len() != MD5_SIZE {
-                    bail!("Got invalid hash, size > {} bytes.", MD5_SIZE);
-                }
+        Ok(base)
+    }
 
-                let n1 = match u64::from_str_radix(&line[..16], 16) {
-                    Ok(n) => n,
-                    Err(_) => bail!("Failed to parse \"{}\" as pair u64.", line),
-                };
-                let n2 = match u64::from_str_radix(&line[16..], 16) {
-                    Ok(n) => n,
-                    Err(_) => bail!("Failed to parse \"{}\" as pair u64.", line),
-                };
+    //
+    // Load the named, read-only hash sets after the default set.
+    //
+    fn load_hash_sets(&self, database: &mut Database) -> Result<()> {
+        for hash_set in &self.hash_sets {
+            info!("Loading {} hash set \"{}\".", hash_set.category.name(), hash_set.name);
 
-                hashes_lock.push((n1, n2));
+            let mut set = NamedSet::new(&hash_set.name, hash_set.category);
+            for hash_file in &hash_set.files {
+                set.add(self.read_hash_file(hash_file)?);
             }
+            database.add_set(set);
         }
-        let hashes_count_old = hashes_lock.len();
+        Ok(())
+    }
 
-        //
-        // Insert the new hashes within the database
-        //
-        info!("Inserting new hashes within the database.");
-        for new_hash in new_hashes {
-            match hashes_lock.binary_search(&new_hash) {
-                Ok(_) => {},
-                Err(pos) => hashes_lock.insert(pos, new_hash),
-            }
+    //
+    // Load the optional NSRL metadata side table for all loaded algorithms.
+    //
+    fn load_metadata(&self, database: &mut Database) -> Result<()> {
+        if self.args.metadata.is_empty() {
+            return Ok(());
         }
-        let hashes_count_new = hashes_lock.len();
-        info!("Finished inserting all new hashes.");
-        info!("Total new hashes added: {}.", hashes_count_new - hashes_count_old);
 
-        //
-        // Write changes to disk
-        //
-        if hashes_count_new - hashes_count_old > 0 {
-            info!("Attempting to write changes to disk.");
-            // Open with write (to overwrite)
-            let mut hash_file = std::fs::OpenOptions::new()
-                .write(true)
-                .open(&self.args.hash_file)?;
-            // Slow AF, is there a better way to do this??
-            // tbh. we should't care as merging should not be performed often.
-            for hash in hashes_lock.iter() {
-                match hash_file.write_fmt(
-                    format_args!("{:016X}{:016X}\n", hash.0, hash.1)) {
-                    Ok(_) => {},
-                    Err(error) => {
-                        error!("Failed to write changes to hash file.");
-                        bail!("{}", error);
-                    }
-                };
-            }
-        } else {
-            info!("No new hashes added, nothing to write to disk.");
-        }
+        info!("Loading NSRL metadata from \"{}\".", self.args.metadata);
+        database.set_metadata(Metadata::load(&self.args.metadata, &database.algorithms())?);
+        Ok(())
+    }
 
-        //
-        // Remove change files after all is done
-        //
-        info!("Attempting to clean up change files.");
-        for path in &paths {
-            match fs::remove_file(path.path()) {
-                Ok(_) => info!("Removed change file \"{}\".", path.path().display()),
-                Err(error) => {
-                    error!("Failed to remove change file \"{}\".", path.path().display());
-                    // No point in bailing here
-                    error!("{}", error);
-                } 
+    fn read_hash_file(&self, hash_file: &HashFile) -> Result<HashTable> {
+        info!("Reading {} hash file \"{}\".", hash_file.algorithm.name(), hash_file.path);
+        ingest::read_hash_file(hash_file.algorithm, &hash_file.path, &self.read_options())
+    }
 
-            }
+    fn read_options(&self) -> ingest::ReadOptions {
+        ingest::ReadOptions {
+            require_sorted: self.args.require_sorted,
+            mmap: self.args.mmap,
         }
+    }
 
-        let elapsed = now.elapsed();
+    fn run_command(command: &args::Command) -> Result<()> {
+        match command {
+            args::Command::Convert(convert) => {
+                // Value is restricted by the argument parser
+                let algorithm = HashAlgorithm::from_name(&convert.hash_type)
+                    .unwrap_or(HashAlgorithm::Md5);
 
-        info!("Finished merging hashes.");
-        info!("Total time taken: {:.2?}.", elapsed);
-        
+                info!("Converting {} hash file \"{}\" to binary index \"{}\".",
+                    algorithm.name(), convert.input, convert.output);
+
+                let now = Instant::now();
+
+                let options = ingest::ReadOptions::default();
+                let hashes = ingest::read_hash_file(algorithm, &convert.input, &options)?;
+                index::write_index(&convert.output, &hashes)?;
+
+                info!("Wrote {} hashes.", hashes.len());
+                info!("Total time taken: {:.2?}.", now.elapsed());
+            },
+            args::Command::Snapshot(snapshot) => {
+                info!("Requesting snapshot \"{}\" from \"{}:{}\".",
+                    snapshot.name, snapshot.host, snapshot.port);
+
+                let count = snapshot::send_command(&snapshot.host, snapshot.port, b'n', &snapshot.name)?;
+
+                info!("Server wrote snapshot of {} hashes.", count);
+            },
+            args::Command::Restore(restore) => {
+                info!("Requesting restore of snapshot \"{}\" from \"{}:{}\".",
+                    restore.name, restore.host, restore.port);
+
+                let count = snapshot::send_command(&restore.host, restore.port, b'r', &restore.name)?;
+
+                info!("Server restored {} hashes.", count);
+            },
+        }
         Ok(())
     }
 
@@ -345,24 +509,25 @@ impl Server {
 
         let now = Instant::now();
 
-        if self.args.test.len() != MD5_SIZE {
-            bail!("Got invalid hash, size > {} bytes.", MD5_SIZE);
-        }
-
-        let n1 = match u64::from_str_radix(&self.args.test[..16], 16) {
-            Ok(n) => n,
-            Err(_) => bail!("Failed to parse \"{}\" as pair u64.", self.args.test),
-        };
-        let n2 = match u64::from_str_radix(&self.args.test[16..], 16) {
-            Ok(n) => n,
-            Err(_) => bail!("Failed to parse \"{}\" as pair u64.", self.args.test),
+        let algorithm = match HashAlgorithm::from_hex_size(self.args.test.len()) {
+            Some(algorithm) => algorithm,
+            None => bail!("Got invalid hash \"{}\".", self.args.test),
         };
 
-        let hashes: proto::HashDatabase = Arc::clone(&self.hashes);
-        let hashes_lock = hashes.try_lock().unwrap();
-        match hashes_lock.binary_search(&(n1, n2)) {
-            Ok(pos) => info!("Test hash found at position {}.", pos + 1),
-            Err(_) => info!("Test hash not found."),
+        let mut buffer = [0u8; MAX_DIGEST_SIZE];
+        let digest = &mut buffer[..algorithm.digest_size()];
+        hash::parse_hash(algorithm, &self.args.test, digest)?;
+
+        for set in self.hashes.sets() {
+            let table = match set.get(algorithm) {
+                Some(table) => table.snapshot(),
+                None => continue,
+            };
+            match table.contains(digest) {
+                true => info!("Test hash found in {} hash set \"{}\".",
+                    set.category().name(), set.name()),
+                false => info!("Test hash not found in hash set \"{}\".", set.name()),
+            }
         }
         
         let elapsed = now.elapsed();
@@ -379,6 +544,22 @@ impl Server {
 
         let listener = TcpListener::bind(&address).await?;
 
+        if self.args.nsrl_port > 0 {
+            let address = format!("{}:{}", self.args.host, self.args.nsrl_port);
+            info!("Starting nsrlsvr compatible server on \"{}\".", address);
+            Self::serve_nsrl(TcpListener::bind(&address).await?, Arc::clone(&self.hashes));
+        }
+
+        if self.args.http_port > 0 {
+            let address = format!("{}:{}", self.args.host, self.args.http_port);
+            info!("Starting HTTP server on \"{}\".", address);
+            Self::serve_http(TcpListener::bind(&address).await?, Arc::clone(&self.hashes));
+        }
+
+        if self.args.merge_interval > 0 {
+            Self::schedule_merges(Arc::clone(&self.hashes), self.args.merge_interval);
+        }
+
         loop {
             let (mut socket, remote_address) = listener.accept().await?;
 
@@ -392,39 +573,80 @@ impl Server {
         }
     }
 
-    fn has_change_files() -> bool {
-        match std::fs::read_dir(globals::CHANGE_FILE_DIR) {
-            Ok(files) => {
-                if files.count() > 0 {
-                    true
-                } else {
-                    false
-                }
-            },
-            Err(_) => false,
-        }
+    //
+    // Accept nsrlsvr protocol connections, see `nsrl.rs`.
+    //
+    fn serve_nsrl(listener: TcpListener, hashes: proto::HashDatabase) {
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, remote_address) = match listener.accept().await {
+                    Ok(connection) => connection,
+                    Err(error) => {
+                        error!("Failed to accept NSRL connection.");
+                        error!("{}", error);
+                        continue;
+                    },
+                };
+
+                info!("Received NSRL connection from {:?}", remote_address);
+
+                let hashes: proto::HashDatabase = Arc::clone(&hashes);
+
+                tokio::spawn(async move {
+                    nsrl::handle_client(&mut socket, &hashes).await;
+                });
+            }
+        });
     }
 
-    fn get_change_file_paths() -> Result<Vec<DirEntry>, Error> {
-        let mut paths: Vec<DirEntry> = vec![];
-        for path in fs::read_dir(globals::CHANGE_FILE_DIR)? {
-            match path {
-                Ok(path) => paths.push(path),
-                Err(error) => bail!(error),
+    //
+    // Accept HTTP connections, see `http.rs`.
+    //
+    fn serve_http(listener: TcpListener, hashes: proto::HashDatabase) {
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, remote_address) = match listener.accept().await {
+                    Ok(connection) => connection,
+                    Err(error) => {
+                        error!("Failed to accept HTTP connection.");
+                        error!("{}", error);
+                        continue;
+                    },
+                };
+
+                info!("Received HTTP connection from {:?}", remote_address);
+
+                let hashes: proto::HashDatabase = Arc::clone(&hashes);
+
+                tokio::spawn(async move {
+                    http::handle_client(&mut socket, &hashes).await;
+                });
             }
-        }
-        Ok(paths)
+        });
     }
 
-    fn backup_hash_file(&self) -> Result<()> {
-        let backup_file_name = self.args.hash_file.clone() + ".bak";
-        info!("Backing up hash file to \"{}\".", backup_file_name);
-        match std::fs::copy(self.args.hash_file.clone(), backup_file_name) {
-            Ok(_) => Ok(()),
-            Err(error) => {
-                error!("Failed to backup hash file.");
-                bail!(error)
+    //
+    // Merge change files periodically while serving.
+    //
+    fn schedule_merges(hashes: proto::HashDatabase, seconds: u64) {
+        info!("Merging change files every {} seconds.", seconds);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(seconds));
+            // The first tick completes immediately
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                if !wal::has_change_files(&hashes.layout().change_file_dir()) {
+                    continue;
+                }
+                match merge::merge(Arc::clone(&hashes)).await {
+                    Ok(_) => {},
+                    Err(error) => {
+                        error!("Failed to merge change files.");
+                        error!("{}", error);
+                    },
+                }
             }
-        }
+        });
     }
 }
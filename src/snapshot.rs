@@ -0,0 +1,232 @@
+use anyhow::{Result, bail};
+
+use std::fs;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use log::{info, warn};
+
+use crate::database::Database;
+use crate::hash::HashAlgorithm;
+use crate::index;
+use crate::ingest::HashFile;
+use crate::merge;
+use crate::proto::{self, HashDatabase};
+use crate::replication::Replication;
+use crate::utils;
+use crate::wal;
+
+//
+// Named point-in-time snapshots of the default set, base & runtime changes.
+//
+// A snapshot is a directory in the snapshot directory (see `layout.rs`)
+// holding one binary index per algorithm, `<algorithm>.idx`. It is written
+// to a temporary directory & renamed once complete, so a snapshot either
+// exists in full or not at all. Snapshots are never overwritten.
+//
+// Restoring a snapshot drops all change files & replaces the hash files by
+// the snapshot indexes, backing them up as a merge does. The change files
+// are removed first, as replaying them over a restored hash file would undo
+// the restore, a restore that fails after that can simply be retried.
+// Followers start over afterwards, see `replication.rs`.
+//
+// Both wait for running updates & merges, queries keep being served.
+//
+
+//
+// Snapshot names are used as directory names.
+//
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with('.')
+        && name.bytes().all(|byte| byte.is_ascii_alphanumeric() || b"-_.".contains(&byte))
+}
+
+//
+// Run a snapshot on a blocking thread.
+// Returns the number of hashes in the snapshot.
+//
+pub async fn snapshot(hashes: HashDatabase, name: String) -> Result<usize> {
+    match tokio::task::spawn_blocking(move || write_snapshot(&hashes, &name)).await {
+        Ok(result) => result,
+        Err(error) => bail!(error),
+    }
+}
+
+//
+// Run a restore on a blocking thread.
+// Returns the number of restored hashes.
+//
+pub async fn restore(hashes: HashDatabase, name: String) -> Result<usize> {
+    match tokio::task::spawn_blocking(move || restore_snapshot(&hashes, &name)).await {
+        Ok(result) => result,
+        Err(error) => bail!(error),
+    }
+}
+
+pub fn write_snapshot(database: &Database, name: &str) -> Result<usize> {
+    if !is_valid_name(name) {
+        bail!("Got invalid snapshot name \"{}\".", name);
+    }
+
+    let snapshot_dir = database.layout().snapshot_dir();
+    let path = snapshot_dir.join(name);
+    if path.exists() {
+        bail!("Snapshot \"{}\" already exists.", name);
+    }
+
+    let now = Instant::now();
+
+    info!("Taking snapshot \"{}\".", name);
+
+    //
+    // Take the snapshots of all tables at once, between change files
+    //
+    let snapshots = {
+        let _change_log = database.change_log().blocking_lock();
+        let mut snapshots = vec![];
+        for hash_file in database.files() {
+            match database.get(hash_file.algorithm) {
+                Some(table) => snapshots.push((hash_file.algorithm, table.snapshot())),
+                None => bail!("No {} hashes are loaded.", hash_file.algorithm.name()),
+            }
+        }
+        snapshots
+    };
+
+    //
+    // Write all indexes to a temporary directory, then rename it
+    //
+    let temporary_path = snapshot_dir.join(format!(".{}.tmp", name));
+    if temporary_path.exists() {
+        warn!("Removing incomplete snapshot \"{}\".", temporary_path.display());
+        fs::remove_dir_all(&temporary_path)?;
+    }
+    fs::create_dir_all(&temporary_path)?;
+
+    let mut total = 0;
+    for (algorithm, snapshot) in snapshots {
+        let table = snapshot.to_table();
+        drop(snapshot);
+
+        let index_file = HashFile {
+            algorithm,
+            path: index_path(&temporary_path, algorithm).display().to_string(),
+        };
+        merge::write_hash_file(&index_file, &table, true)?;
+
+        info!("Wrote {} {} hashes.", table.len(), algorithm.name());
+        total += table.len();
+    }
+
+    fs::rename(&temporary_path, &path)?;
+    utils::sync_directory(&snapshot_dir)?;
+
+    info!("Finished snapshot \"{}\" of {} hashes.", name, total);
+    info!("Total time taken: {:.2?}.", now.elapsed());
+
+    Ok(total)
+}
+
+pub fn restore_snapshot(database: &Database, name: &str) -> Result<usize> {
+    if !is_valid_name(name) {
+        bail!("Got invalid snapshot name \"{}\".", name);
+    }
+
+    let path = database.layout().snapshot_dir().join(name);
+    if !path.is_dir() {
+        bail!("Snapshot \"{}\" not found.", name);
+    }
+
+    let _change_log = database.change_log().blocking_lock();
+
+    let now = Instant::now();
+
+    info!("Restoring snapshot \"{}\".", name);
+
+    //
+    // Read & check the whole snapshot before touching any hash file
+    //
+    for entry in fs::read_dir(&path)? {
+        let file_name = entry?.file_name().to_string_lossy().into_owned();
+        let algorithm = file_name.strip_suffix(".idx").and_then(HashAlgorithm::from_name);
+        match algorithm {
+            Some(algorithm) if database.files().iter().any(|file| file.algorithm == algorithm) => {},
+            Some(algorithm) => {
+                bail!("Snapshot holds {} hashes but no {} hash file is loaded.",
+                    algorithm.name(), algorithm.name())
+            },
+            None => bail!("Snapshot holds unknown file \"{}\".", file_name),
+        }
+    }
+
+    let mut tables = vec![];
+    for hash_file in database.files() {
+        let index_path = index_path(&path, hash_file.algorithm);
+        if !index_path.exists() {
+            bail!("Snapshot holds no {} hashes.", hash_file.algorithm.name());
+        }
+        merge::check_writable(hash_file)?;
+        tables.push((hash_file, index::read_index(hash_file.algorithm, &index_path)?));
+    }
+
+    //
+    // Drop the changes made since, then replace the hash files
+    //
+    let paths = wal::change_file_paths(&database.layout().change_file_dir())?;
+    merge::remove_change_files(database, &paths)?;
+
+    let mut total = 0;
+    let mut result = Ok(());
+    for (hash_file, table) in tables {
+        info!("Restoring {} {} hashes.", table.len(), hash_file.algorithm.name());
+        total += table.len();
+        result = merge::replace_hash_file(database, hash_file, table);
+        if result.is_err() {
+            break;
+        }
+    }
+
+    // Followers start over from whatever was swapped in, even on failure
+    database.replicate(Replication::Reset);
+    result?;
+
+    info!("Finished restoring snapshot \"{}\" of {} hashes.", name, total);
+    info!("Total time taken: {:.2?}.", now.elapsed());
+
+    Ok(total)
+}
+
+fn index_path(directory: &Path, algorithm: HashAlgorithm) -> PathBuf {
+    directory.join(algorithm.name().to_owned() + ".idx")
+}
+
+//
+// Send a snapshot or restore command to a running server, see `proto.rs`.
+// Returns the number of hashes reported by the server.
+//
+pub fn send_command(host: &str, port: u16, command: u8, name: &str) -> Result<u32> {
+    if !is_valid_name(name) || name.len() > u8::MAX as usize {
+        bail!("Got invalid snapshot name \"{}\".", name);
+    }
+
+    let mut socket = TcpStream::connect((host, port))?;
+
+    let mut request = vec![b'1', command, name.len() as u8];
+    request.extend_from_slice(name.as_bytes());
+    request.extend_from_slice(b"1e");
+    socket.write_all(&request)?;
+
+    let mut response = vec![];
+    socket.read_to_end(&mut response)?;
+
+    match response.first() {
+        Some(b's') if response.len() >= 5 => {
+            Ok(u32::from_be_bytes([response[1], response[2], response[3], response[4]]))
+        },
+        Some(b'e') => bail!("Server responded with \"{}\".", proto::decode_error(&response[1..])),
+        _ => bail!("Got invalid response from server."),
+    }
+}
@@ -0,0 +1,433 @@
+use anyhow::{Result, bail};
+
+use std::fs::File;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+
+//
+// Minimal read-only reader for SQLite 3 database files.
+// Only supports full table scans of rowid & WITHOUT ROWID tables, which is
+// all that is needed to ingest NSRL RDSv3 releases.
+// See https://www.sqlite.org/fileformat2.html for the format.
+//
+
+pub const SQLITE_MAGIC: &[u8; 16] = b"SQLite format 3\0";
+
+const HEADER_SIZE: usize = 100;
+const ENCODING_UTF8: u32 = 1;
+
+const PAGE_INTERIOR_INDEX: u8 = 0x02;
+const PAGE_INTERIOR_TABLE: u8 = 0x05;
+const PAGE_LEAF_INDEX: u8 = 0x0A;
+const PAGE_LEAF_TABLE: u8 = 0x0D;
+
+#[derive(Debug, Clone, Copy)]
+pub enum Value<'a> {
+    Null,
+    Integer(i64),
+    // Floating point values are never needed for ingestion
+    Real,
+    Text(&'a [u8]),
+    Blob(&'a [u8]),
+}
+
+pub struct Table {
+    root_page: u32,
+    columns: Vec<String>,
+    // Position of each declared column within the stored record
+    record_order: Vec<usize>,
+    // Column aliasing the rowid (INTEGER PRIMARY KEY), stored as NULL
+    rowid_column: Option<usize>,
+}
+
+impl Table {
+    pub fn column(&self, name: &str) -> Option<usize> {
+        self.columns.iter().position(|column| column.eq_ignore_ascii_case(name))
+    }
+}
+
+pub struct Database {
+    file: File,
+    page_size: usize,
+    usable_size: usize,
+}
+
+impl Database {
+    pub fn open<P>(path: P) -> Result<Self>
+    where P: AsRef<Path>, {
+        let file = File::open(path)?;
+
+        let mut header = [0u8; HEADER_SIZE];
+        file.read_exact_at(&mut header, 0)?;
+
+        if &header[..16] != SQLITE_MAGIC {
+            bail!("Not a SQLite 3 database.");
+        }
+
+        let page_size = match u16::from_be_bytes([header[16], header[17]]) {
+            1 => 65536,
+            size => size as usize,
+        };
+        if page_size < 512 || !page_size.is_power_of_two() {
+            bail!("Got invalid SQLite page size {}.", page_size);
+        }
+
+        let encoding = u32::from_be_bytes([header[56], header[57], header[58], header[59]]);
+        if encoding != ENCODING_UTF8 {
+            bail!("Only UTF-8 SQLite databases are supported.");
+        }
+
+        Ok(Database {
+            file,
+            page_size,
+            usable_size: page_size - header[20] as usize,
+        })
+    }
+
+    //
+    // Look up a table in `sqlite_master` & parse its column layout.
+    //
+    pub fn table(&self, name: &str) -> Result<Table> {
+        // type, name, tbl_name, rootpage, sql
+        let master = Table {
+            root_page: 1,
+            columns: vec![],
+            record_order: vec![0, 1, 2, 3, 4],
+            rowid_column: None,
+        };
+
+        let mut found: Option<(u32, String)> = None;
+        self.scan(&master, |row| {
+            if let (Value::Text(b"table"), Value::Text(table_name), Value::Integer(root), Value::Text(sql))
+                = (row[0], row[1], row[3], row[4])
+                && table_name.eq_ignore_ascii_case(name.as_bytes()) {
+                found = Some((root as u32, String::from_utf8_lossy(sql).into_owned()));
+            }
+            Ok(())
+        })?;
+
+        match found {
+            Some((root_page, sql)) => parse_table(root_page, &sql),
+            None => bail!("Table \"{}\" not found in SQLite database.", name),
+        }
+    }
+
+    //
+    // Call `callback` for every row of `table`, values in declared column order.
+    // Rows are visited in page order, not in rowid or key order.
+    //
+    pub fn scan<F>(&self, table: &Table, mut callback: F) -> Result<()>
+    where F: FnMut(&[Value]) -> Result<()>, {
+        let mut page = vec![0u8; self.page_size];
+        let mut payload: Vec<u8> = vec![];
+        let mut pages: Vec<u32> = vec![table.root_page];
+
+        while let Some(number) = pages.pop() {
+            self.read_page(number, &mut page)?;
+
+            // Page 1 starts with the database header
+            let start = if number == 1 { HEADER_SIZE } else { 0 };
+            let page_type = page[start];
+            let cell_count = read_u16(&page, start + 3) as usize;
+            let header_size = match page_type {
+                PAGE_INTERIOR_INDEX | PAGE_INTERIOR_TABLE => {
+                    pages.push(read_u32(&page, start + 8));
+                    12
+                },
+                PAGE_LEAF_INDEX | PAGE_LEAF_TABLE => 8,
+                _ => bail!("Got invalid SQLite page type {} on page {}.", page_type, number),
+            };
+
+            for cell in 0..cell_count {
+                let mut offset = read_u16(&page, start + header_size + cell * 2) as usize;
+
+                if page_type == PAGE_INTERIOR_INDEX || page_type == PAGE_INTERIOR_TABLE {
+                    pages.push(read_u32(&page, offset));
+                    offset += 4;
+                }
+                if page_type == PAGE_INTERIOR_TABLE {
+                    // Interior table cells only hold a key, no payload
+                    continue;
+                }
+
+                let (payload_size, size) = read_varint(&page, offset)?;
+                offset += size;
+
+                let mut rowid = 0;
+                if page_type == PAGE_LEAF_TABLE {
+                    let (value, size) = read_varint(&page, offset)?;
+                    rowid = value as i64;
+                    offset += size;
+                }
+
+                self.read_payload(&page, offset, payload_size as usize,
+                    page_type == PAGE_LEAF_TABLE, &mut payload)?;
+
+                let record = parse_record(&payload)?;
+                let row: Vec<Value> = (0..table.record_order.len()).map(|column| {
+                    if table.rowid_column == Some(column) {
+                        Value::Integer(rowid)
+                    } else {
+                        // Columns added by ALTER TABLE may be missing
+                        record.get(table.record_order[column]).copied().unwrap_or(Value::Null)
+                    }
+                }).collect();
+
+                callback(&row)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn read_page(&self, number: u32, page: &mut [u8]) -> Result<()> {
+        if number == 0 {
+            bail!("Got invalid SQLite page number 0.");
+        }
+        let offset = (number as u64 - 1) * self.page_size as u64;
+        self.file.read_exact_at(page, offset)?;
+        Ok(())
+    }
+
+    //
+    // Collect a cell payload, following the overflow page chain if the
+    // payload does not fit within the page.
+    //
+    fn read_payload(
+        &self,
+        page: &[u8],
+        offset: usize,
+        size: usize,
+        table_leaf: bool,
+        payload: &mut Vec<u8>,
+    ) -> Result<()> {
+        let usable = self.usable_size;
+        let max_local = if table_leaf {
+            usable - 35
+        } else {
+            ((usable - 12) * 64 / 255) - 23
+        };
+        let min_local = ((usable - 12) * 32 / 255) - 23;
+
+        let local = if size <= max_local {
+            size
+        } else {
+            let local = min_local + ((size - min_local) % (usable - 4));
+            if local <= max_local { local } else { min_local }
+        };
+
+        payload.clear();
+        match page.get(offset..offset + local) {
+            Some(bytes) => payload.extend_from_slice(bytes),
+            None => bail!("Got invalid SQLite cell payload."),
+        }
+        if local == size {
+            return Ok(());
+        }
+
+        let mut overflow = vec![0u8; self.page_size];
+        let mut next = read_u32(page, offset + local);
+        while payload.len() < size {
+            self.read_page(next, &mut overflow)?;
+            next = read_u32(&overflow, 0);
+            let remaining = (size - payload.len()).min(usable - 4);
+            payload.extend_from_slice(&overflow[4..4 + remaining]);
+        }
+
+        Ok(())
+    }
+}
+
+fn parse_record(payload: &[u8]) -> Result<Vec<Value<'_>>> {
+    let (header_size, size) = read_varint(payload, 0)?;
+    let header_size = header_size as usize;
+
+    let mut values = vec![];
+    let mut header_offset = size;
+    let mut body_offset = header_size;
+
+    while header_offset < header_size {
+        let (serial_type, size) = read_varint(payload, header_offset)?;
+        header_offset += size;
+
+        let length = match serial_type {
+            0 | 8 | 9 => 0,
+            1 => 1,
+            2 => 2,
+            3 => 3,
+            4 => 4,
+            5 => 6,
+            6 | 7 => 8,
+            10 | 11 => bail!("Got reserved SQLite serial type {}.", serial_type),
+            _ => ((serial_type - 12) / 2) as usize,
+        };
+        let bytes = match payload.get(body_offset..body_offset + length) {
+            Some(bytes) => bytes,
+            None => bail!("Got truncated SQLite record."),
+        };
+        body_offset += length;
+
+        values.push(match serial_type {
+            0 => Value::Null,
+            1..=6 => {
+                // Big-endian two's complement, sign extended
+                let mut value: i64 = if bytes[0] & 0x80 != 0 { -1 } else { 0 };
+                for byte in bytes {
+                    value = (value << 8) | *byte as i64;
+                }
+                Value::Integer(value)
+            },
+            7 => Value::Real,
+            8 => Value::Integer(0),
+            9 => Value::Integer(1),
+            _ if serial_type % 2 == 0 => Value::Blob(bytes),
+            _ => Value::Text(bytes),
+        });
+    }
+
+    Ok(values)
+}
+
+//
+// Derive the stored column layout from a `CREATE TABLE` statement.
+//
+fn parse_table(root_page: u32, sql: &str) -> Result<Table> {
+    let (open, close) = match (sql.find('('), sql.rfind(')')) {
+        (Some(open), Some(close)) if open < close => (open, close),
+        _ => bail!("Failed to parse table definition \"{}\".", sql),
+    };
+    let without_rowid = sql[close..].to_ascii_uppercase().contains("WITHOUT ROWID");
+
+    let mut columns: Vec<String> = vec![];
+    let mut primary_key: Vec<String> = vec![];
+    let mut rowid_column = None;
+
+    for definition in split_definitions(&sql[open + 1..close]) {
+        let (name, rest) = split_name(definition.trim());
+        let rest = rest.to_ascii_uppercase();
+
+        match name.to_ascii_uppercase().as_str() {
+            "CONSTRAINT" | "UNIQUE" | "CHECK" | "FOREIGN" => continue,
+            "PRIMARY" => {
+                if let (Some(open), Some(close)) = (definition.find('('), definition.rfind(')')) {
+                    for key in definition[open + 1..close].split(',') {
+                        primary_key.push(split_name(key.trim()).0);
+                    }
+                }
+                continue;
+            },
+            _ => {},
+        }
+
+        if rest.contains("PRIMARY KEY") {
+            primary_key = vec![name.clone()];
+            if !without_rowid && rest.split_whitespace().next() == Some("INTEGER") {
+                rowid_column = Some(columns.len());
+            }
+        }
+        columns.push(name);
+    }
+
+    //
+    // WITHOUT ROWID records store the primary key columns first,
+    // followed by the remaining columns in declared order.
+    //
+    let record_order = if without_rowid {
+        let mut stored: Vec<usize> = primary_key.iter()
+            .filter_map(|key| columns.iter().position(|column| column.eq_ignore_ascii_case(key)))
+            .collect();
+        for column in 0..columns.len() {
+            if !stored.contains(&column) {
+                stored.push(column);
+            }
+        }
+        let mut order = vec![0; columns.len()];
+        for (position, column) in stored.iter().enumerate() {
+            order[*column] = position;
+        }
+        order
+    } else {
+        (0..columns.len()).collect()
+    };
+
+    Ok(Table { root_page, columns, record_order, rowid_column })
+}
+
+//
+// Split on top level commas, ignoring nested parentheses & quotes.
+//
+fn split_definitions(body: &str) -> Vec<&str> {
+    let mut definitions = vec![];
+    let mut depth = 0;
+    let mut quote: Option<char> = None;
+    let mut start = 0;
+
+    for (i, c) in body.char_indices() {
+        match (quote, c) {
+            (Some(q), _) if c == q => quote = None,
+            (Some(_), _) => {},
+            (None, '"' | '\'' | '`') => quote = Some(c),
+            (None, '[') => quote = Some(']'),
+            (None, '(') => depth += 1,
+            (None, ')') => depth -= 1,
+            (None, ',') if depth == 0 => {
+                definitions.push(&body[start..i]);
+                start = i + 1;
+            },
+            _ => {},
+        }
+    }
+    definitions.push(&body[start..]);
+    definitions
+}
+
+//
+// Split a leading, possibly quoted, identifier from the rest of a definition.
+//
+fn split_name(definition: &str) -> (String, &str) {
+    let close = match definition.chars().next() {
+        Some('"') => Some('"'),
+        Some('`') => Some('`'),
+        Some('[') => Some(']'),
+        _ => None,
+    };
+    match close {
+        Some(close) => match definition[1..].find(close) {
+            Some(end) => (definition[1..end + 1].to_owned(), &definition[end + 2..]),
+            None => (definition[1..].to_owned(), ""),
+        },
+        None => {
+            let end = definition.find(char::is_whitespace).unwrap_or(definition.len());
+            (definition[..end].to_owned(), &definition[end..])
+        },
+    }
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_be_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes([bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]])
+}
+
+//
+// Returns the value & the amount of bytes used.
+//
+fn read_varint(bytes: &[u8], offset: usize) -> Result<(u64, usize)> {
+    let mut value: u64 = 0;
+    for i in 0..9 {
+        let byte = match bytes.get(offset + i) {
+            Some(byte) => *byte,
+            None => bail!("Got truncated SQLite varint."),
+        };
+        if i == 8 {
+            return Ok(((value << 8) | byte as u64, 9));
+        }
+        value = (value << 7) | (byte & 0x7F) as u64;
+        if byte & 0x80 == 0 {
+            return Ok((value, i + 1));
+        }
+    }
+    unreachable!()
+}
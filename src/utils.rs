@@ -26,11 +26,68 @@ where P: AsRef<Path>, {
     Ok(file.metadata()?.size())
 }
 
-pub fn change_file_name() -> Result<String> {
+//
+// Sync a directory, which persists the creation, removal & renaming of the
+// files in it.
+//
+pub fn sync_directory<P>(path: P) -> io::Result<()>
+where P: AsRef<Path>, {
+    File::open(path)?.sync_all()
+}
+
+//
+// Current time as "seconds.nanoseconds", used to name change files & backups.
+//
+pub fn timestamp() -> Result<String> {
     let epoch = match SystemTime::now().duration_since(UNIX_EPOCH) {
         Ok(epoch) => epoch,
         Err(error) => bail!(error),
     };
 
-    Ok(format!("{}.{}.txt", epoch.as_secs(), epoch.subsec_nanos()))
+    Ok(format!("{}.{:09}", epoch.as_secs(), epoch.subsec_nanos()))
+}
+
+pub fn change_file_name() -> Result<String> {
+    Ok(timestamp()? + ".wal")
+}
+
+//
+// Time of a name starting with a `timestamp`, (0, 0) if it has none.
+// Change files are replayed in this order, as deletions & insertions of the
+// same hash must be applied in the order they were made.
+//
+pub fn file_time(name: &str) -> (u64, u32) {
+    let mut parts = name.split('.');
+    match (parts.next().map(str::parse), parts.next().map(str::parse)) {
+        (Some(Ok(secs)), Some(Ok(nanos))) => (secs, nanos),
+        _ => (0, 0),
+    }
 }
+
+//
+// CRC-32 (IEEE 802.3), as used by zlib & gzip.
+// Pass 0 as `crc` to start, or a previous result to continue over more data.
+//
+pub fn crc32(crc: u32, bytes: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for byte in bytes {
+        crc = CRC32_TABLE[((crc ^ *byte as u32) & 0xFF) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 { 0xEDB88320 ^ (crc >> 1) } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
@@ -0,0 +1,318 @@
+use anyhow::{Result, bail};
+
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use log::{info, warn};
+
+use crate::database::HashTable;
+use crate::hash::{self, HashAlgorithm, MAX_DIGEST_SIZE};
+use crate::ingest::{self, HashFile};
+use crate::utils;
+
+//
+// Change files are the write-ahead log of runtime updates & deletions, one
+// file per update or delete command, named after its creation time.
+//
+// +---------+---------+----------+
+// |  Magic  | Version | Reserved |
+// +---------+---------+----------+
+// | 8 bytes | u16be   | 6 bytes  |
+// +---------+---------+----------+
+//
+// Followed by records:
+//
+// +--------+-----------+-------------------+-----------------+
+// |   Op   | Algorithm |      Digest       |     Checksum    |
+// +--------+-----------+-------------------+-----------------+
+// | 1 byte | 1 byte    | Digest size bytes | 4 bytes (u32be) |
+// +--------+-----------+-------------------+-----------------+
+//
+// The op is "+" for insertions & "-" for deletions, the algorithm uses the
+// ids of the binary index & the checksum is the CRC-32 of the op, algorithm
+// & digest. A change file is synced before the client is answered.
+//
+// The first incomplete or corrupt record ends a change file, as that is
+// what a crash in the middle of a write leaves behind. Such torn tails are
+// truncated on startup instead of refusing to start.
+//
+// Change files of older versions, one hex hash per line & deletions
+// prefixed with "-", are still read.
+//
+
+const CHANGE_FILE_MAGIC: &[u8; 8] = b"DUHASHCL";
+const CHANGE_FILE_HEADER_SIZE: usize = 16;
+const CHANGE_FILE_VERSION: u16 = 1;
+
+const OP_INSERT: u8 = b'+';
+const OP_DELETE: u8 = b'-';
+
+pub struct Change {
+    pub insert: bool,
+    pub algorithm: HashAlgorithm,
+    pub digest: Vec<u8>,
+}
+
+pub struct ChangeFile {
+    pub changes: Vec<Change>,
+    // Size of the valid part, anything after it is a torn tail
+    valid_size: usize,
+    size: usize,
+}
+
+impl ChangeFile {
+    pub fn is_torn(&self) -> bool {
+        self.valid_size < self.size
+    }
+}
+
+//
+// Write the changes of a command to a new change file & sync it,
+// including the directory entry of the file.
+//
+pub fn write_change_file(
+    directory: &Path,
+    file: &mut File,
+    insert: bool,
+    algorithm: HashAlgorithm,
+    digests: &[Vec<u8>],
+) -> io::Result<()> {
+    let mut buffer = Vec::with_capacity(
+        CHANGE_FILE_HEADER_SIZE + digests.len() * record_size(algorithm));
+
+    buffer.extend_from_slice(CHANGE_FILE_MAGIC);
+    buffer.extend_from_slice(&CHANGE_FILE_VERSION.to_be_bytes());
+    buffer.resize(CHANGE_FILE_HEADER_SIZE, 0);
+
+    encode_records(insert, algorithm, digests, &mut buffer);
+
+    file.write_all(&buffer)?;
+    file.sync_all()?;
+    utils::sync_directory(directory)
+}
+
+pub fn record_size(algorithm: HashAlgorithm) -> usize {
+    2 + algorithm.digest_size() + 4
+}
+
+//
+// Append a record per digest, also used to replicate changes to followers.
+//
+pub fn encode_records(
+    insert: bool,
+    algorithm: HashAlgorithm,
+    digests: &[Vec<u8>],
+    buffer: &mut Vec<u8>,
+) {
+    for digest in digests {
+        let start = buffer.len();
+        buffer.push(if insert { OP_INSERT } else { OP_DELETE });
+        buffer.push(algorithm.id());
+        buffer.extend_from_slice(digest);
+        let checksum = utils::crc32(0, &buffer[start..]);
+        buffer.extend_from_slice(&checksum.to_be_bytes());
+    }
+}
+
+//
+// Decode the record at the start of `record`, None if it is incomplete or
+// corrupt. Returns the change & the record size.
+//
+pub fn decode_record(record: &[u8]) -> Option<(Change, usize)> {
+    let insert = match record.first() {
+        Some(&OP_INSERT) => true,
+        Some(&OP_DELETE) => false,
+        _ => return None,
+    };
+    let algorithm = record.get(1).copied().and_then(HashAlgorithm::from_id)?;
+
+    let size = 2 + algorithm.digest_size();
+    let checksum = record.get(size..size + 4)?;
+    let checksum = u32::from_be_bytes([checksum[0], checksum[1], checksum[2], checksum[3]]);
+    if utils::crc32(0, &record[..size]) != checksum {
+        return None;
+    }
+
+    Some((Change { insert, algorithm, digest: record[2..size].to_vec() }, size + 4))
+}
+
+//
+// Read the valid changes of a change file, in the order they were made.
+//
+pub fn read_change_file<P>(path: P) -> Result<ChangeFile>
+where P: AsRef<Path>, {
+    let bytes = fs::read(path)?;
+
+    let (changes, valid_size) = if bytes.starts_with(CHANGE_FILE_MAGIC) {
+        let version = match bytes.get(8..10) {
+            Some(version) => u16::from_be_bytes([version[0], version[1]]),
+            None => 0,
+        };
+        if bytes.len() >= CHANGE_FILE_HEADER_SIZE && version != CHANGE_FILE_VERSION {
+            bail!("Unsupported change file version {}.", version);
+        }
+        parse_records(&bytes)
+    } else if CHANGE_FILE_MAGIC.starts_with(&bytes) {
+        // Torn header, nothing was logged yet
+        (vec![], 0)
+    } else {
+        parse_text(&bytes)
+    };
+
+    Ok(ChangeFile { changes, valid_size, size: bytes.len() })
+}
+
+fn parse_records(bytes: &[u8]) -> (Vec<Change>, usize) {
+    let mut changes = vec![];
+    let mut offset = CHANGE_FILE_HEADER_SIZE;
+    if bytes.len() < offset {
+        return (changes, 0);
+    }
+
+    while let Some((change, size)) = decode_record(&bytes[offset..]) {
+        changes.push(change);
+        offset += size;
+    }
+
+    (changes, offset)
+}
+
+//
+// Older change files, only lines ending with a newline are complete.
+//
+fn parse_text(bytes: &[u8]) -> (Vec<Change>, usize) {
+    let mut changes = vec![];
+    let mut offset = 0;
+    let mut buffer = [0u8; MAX_DIGEST_SIZE];
+
+    while let Some(end) = bytes[offset..].iter().position(|byte| *byte == b'\n') {
+        let line = String::from_utf8_lossy(&bytes[offset..offset + end]);
+        if let Some(line) = ingest::clean_line(&line) {
+            let (line, insert) = match line.strip_prefix('-') {
+                Some(line) => (line, false),
+                None => (line, true),
+            };
+            let algorithm = match HashAlgorithm::from_hex_size(line.len()) {
+                Some(algorithm) => algorithm,
+                None => break,
+            };
+            let digest = &mut buffer[..algorithm.digest_size()];
+            if hash::parse_hash(algorithm, line, digest).is_err() {
+                break;
+            }
+            changes.push(Change { insert, algorithm, digest: digest.to_vec() });
+        }
+        offset += end + 1;
+    }
+
+    (changes, offset)
+}
+
+//
+// Truncate the torn tails of all change files, removing files left without
+// any change.
+//
+pub fn recover(directory: &Path) -> Result<()> {
+    for path in change_file_paths(directory)? {
+        let change_file = read_change_file(&path)?;
+        if !change_file.is_torn() && !change_file.changes.is_empty() {
+            continue;
+        }
+
+        if change_file.changes.is_empty() {
+            warn!("Removing change file \"{}\" without changes.", path.display());
+            fs::remove_file(&path)?;
+        } else {
+            warn!("Truncating torn change file \"{}\" from {} to {} bytes, kept {} changes.",
+                path.display(), change_file.size, change_file.valid_size,
+                change_file.changes.len());
+            let file = OpenOptions::new().write(true).open(&path)?;
+            file.set_len(change_file.valid_size as u64)?;
+            file.sync_all()?;
+        }
+        utils::sync_directory(directory)?;
+    }
+    info!("Checked change files.");
+    Ok(())
+}
+
+pub fn has_change_files(directory: &Path) -> bool {
+    match fs::read_dir(directory) {
+        Ok(files) => files.count() > 0,
+        Err(_) => false,
+    }
+}
+
+//
+// Change files in the order they were made.
+//
+pub fn change_file_paths(directory: &Path) -> Result<Vec<PathBuf>> {
+    if !fs::exists(directory)? {
+        return Ok(vec![]);
+    }
+
+    let mut paths: Vec<PathBuf> = vec![];
+    for path in fs::read_dir(directory)? {
+        match path {
+            Ok(path) => paths.push(path.path()),
+            Err(error) => bail!(error),
+        }
+    }
+    paths.sort_by_key(|path| {
+        utils::file_time(&path.file_name().unwrap_or_default().to_string_lossy())
+    });
+    Ok(paths)
+}
+
+//
+// Parse change files into the sorted added & removed hashes per algorithm.
+// The latest change of each hash wins.
+//
+pub fn parse_change_files(
+    paths: &[PathBuf],
+    files: &[HashFile],
+) -> Result<HashMap<HashAlgorithm, (HashTable, HashTable)>> {
+    // Latest change of each hash, true for insertions & false for deletions
+    let mut changes: HashMap<HashAlgorithm, HashMap<Vec<u8>, bool>> = HashMap::new();
+
+    for path in paths {
+        info!("Parsing change file \"{}\".", path.display());
+
+        let change_file = read_change_file(path)?;
+        if change_file.is_torn() {
+            warn!("Ignoring torn tail of change file \"{}\".", path.display());
+        }
+
+        for change in change_file.changes {
+            if !files.iter().any(|file| file.algorithm == change.algorithm) {
+                bail!("Got {} hash \"{}\" but no {} hash file is loaded.",
+                    change.algorithm.name(), hash::format_hash(&change.digest),
+                    change.algorithm.name());
+            }
+            changes.entry(change.algorithm)
+                .or_default()
+                .insert(change.digest, change.insert);
+        }
+    }
+
+    let mut tables = HashMap::new();
+    for (algorithm, changes) in changes {
+        let mut added = HashTable::new(algorithm);
+        let mut removed = HashTable::new(algorithm);
+        for (digest, insert) in &changes {
+            match insert {
+                true => added.push(digest),
+                false => removed.push(digest),
+            }
+        }
+        added.sort_and_dedup();
+        removed.sort_and_dedup();
+
+        info!("Got total of {} {} insertions & {} deletions.",
+            added.len(), algorithm.name(), removed.len());
+        tables.insert(algorithm, (added, removed));
+    }
+    Ok(tables)
+}